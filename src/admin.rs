@@ -0,0 +1,497 @@
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::info;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::proxy_health::{HealthState, ProxyHealth};
+use crate::proxy_latency::LatencyHistogram;
+use crate::proxy_list::parse_proxy_line;
+use crate::proxy_pool::{ProxyPool, ProxyStatus};
+use crate::secret::Secret;
+use crate::site_router::SiteRouter;
+
+// Shared by the admin endpoints, requests need `Authorization: Bearer <token>`
+pub struct AdminState {
+    pub token: Secret,
+    pub router: Arc<SiteRouter>,
+}
+
+// Endpoints mounted under /admin. Proxies are identified by their URL
+// without password as listed by GET /admin/pools, sites by name.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/pools", web::get().to(list_pools))
+        .route("/pools/{pool}/proxies", web::post().to(add_proxy))
+        .route("/pools/{pool}/ban", web::post().to(ban))
+        .route("/pools/{pool}/unban", web::post().to(unban))
+        .route("/pools/{pool}/subnets/unban", web::post().to(unban_subnet))
+        .route("/pools/{pool}/drain", web::post().to(drain));
+}
+
+#[derive(Deserialize, Debug)]
+struct AddProxyRequest {
+    // A line in the proxies file format, e.g. `1.2.3.4 country=au`
+    proxy: String,
+    weight: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProxyRequest {
+    proxy: String,
+    site: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SubnetRequest {
+    // As listed in the subnet bans of GET /admin/pools, e.g. `10.0.1.0/24`
+    subnet: String,
+    site: Option<String>,
+}
+
+fn error(status: u16, msg: &str) -> HttpResponse {
+    let body = json!({ "status_code": status, "msg": msg });
+    match status {
+        400 => HttpResponse::BadRequest().json(body),
+        401 => HttpResponse::Unauthorized().json(body),
+        _ => HttpResponse::NotFound().json(body),
+    }
+}
+
+// Compare the whole token whatever the first differing byte
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn authorize(request: &HttpRequest, state: &AdminState) -> Result<(), HttpResponse> {
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if token_matches(state.token.expose(), given) {
+        Ok(())
+    } else {
+        Err(error(401, "Invalid admin token"))
+    }
+}
+
+fn pool(state: &AdminState, name: &str) -> Result<Arc<ProxyPool>, HttpResponse> {
+    state
+        .router
+        .pool(name)
+        .ok_or_else(|| error(404, "Unknown proxy pool"))
+}
+
+fn secs_since(instant: Instant) -> u64 {
+    instant.elapsed().as_secs()
+}
+
+fn health_json(health: &ProxyHealth) -> Value {
+    let now = Instant::now();
+    let (state, remaining_secs) = match health.state {
+        HealthState::Healthy => ("healthy", None),
+        HealthState::Probation { .. } => ("probation", None),
+        HealthState::CoolingDown { until } => {
+            ("cooling_down", Some(until.saturating_duration_since(now)))
+        }
+        HealthState::Quarantined { until } => {
+            ("quarantined", Some(until.saturating_duration_since(now)))
+        }
+    };
+    json!({
+        "state": state,
+        "remaining_secs": remaining_secs.map(|remaining| remaining.as_secs()),
+        "successes": health.successes,
+        "failures": health.failures,
+        "consecutive_failures": health.consecutive_failures,
+    })
+}
+
+fn histogram_json(histogram: &LatencyHistogram) -> Value {
+    let ms = |quantile| histogram.percentile(quantile).map(|p| p.as_millis() as u64);
+    json!({
+        "count": histogram.count(),
+        "mean_ms": histogram.mean().map(|mean| mean.as_millis() as u64),
+        "p50_ms": ms(0.5),
+        "p90_ms": ms(0.9),
+        "p99_ms": ms(0.99),
+    })
+}
+
+fn proxy_json(status: &ProxyStatus) -> Value {
+    json!({
+        "proxy": status.proxy,
+        "weight": status.weight,
+        "tags": status.tags,
+        "subnet": status.subnet,
+        "in_flight": status.in_flight,
+        "draining": status.draining,
+        "last_used_secs_ago": status.last_used.map(secs_since),
+        "probe": status.probe.map(|probe| json!({
+            "reachable": probe.reachable,
+            "latency_ms": probe.latency.as_millis() as u64,
+            "checked_secs_ago": secs_since(probe.checked_at),
+        })),
+        "latency": {
+            "first_byte": histogram_json(&status.latency.first_byte),
+            "total": histogram_json(&status.latency.total),
+        },
+        "banned": status.banned,
+        "health": status
+            .health
+            .iter()
+            .map(|(site, health)| (site.clone(), health_json(health)))
+            .collect::<serde_json::Map<String, Value>>(),
+    })
+}
+
+async fn list_pools(request: HttpRequest, state: web::Data<AdminState>) -> HttpResponse {
+    if let Err(response) = authorize(&request, &state) {
+        return response;
+    }
+    let mut pools = state.router.pools();
+    pools.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let pools: Vec<Value> = pools
+        .iter()
+        .map(|(name, pool)| {
+            let proxies: Vec<Value> = pool.status().iter().map(proxy_json).collect();
+            let subnet_bans: Vec<Value> = pool
+                .subnet_bans()
+                .iter()
+                .map(|(subnet, site, remaining)| {
+                    json!({
+                        "subnet": subnet,
+                        "site": site,
+                        "remaining_secs": remaining.as_secs(),
+                    })
+                })
+                .collect();
+            json!({ "pool": name, "proxies": proxies, "subnet_bans": subnet_bans })
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "pools": pools }))
+}
+
+async fn add_proxy(
+    request: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<AddProxyRequest>,
+    state: web::Data<AdminState>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request, &state) {
+        return response;
+    }
+    let pool = match pool(&state, &path) {
+        Ok(pool) => pool,
+        Err(response) => return response,
+    };
+    let proxy = match parse_proxy_line(&body.proxy) {
+        Ok(Some(proxy)) => proxy,
+        Ok(None) => return error(400, "No proxy given"),
+        Err(message) => return error(400, &message),
+    };
+    // Added proxies are kept until the proxies file of the pool is reloaded
+    match pool.add(&proxy, body.weight.unwrap_or(1)) {
+        Ok(Some(added)) => {
+            info!("Admin added proxy {} to pool {}", added, path);
+            HttpResponse::Ok().json(json!({ "status_code": 200, "proxy": added }))
+        }
+        Ok(None) => error(400, "Proxy already in the pool"),
+        Err(message) => error(400, &message),
+    }
+}
+
+// Pool and site of a ban or unban
+fn ban_target(
+    state: &AdminState,
+    pool_name: &str,
+    site: &Option<String>,
+) -> Result<(Arc<ProxyPool>, String), HttpResponse> {
+    let pool = pool(state, pool_name)?;
+    let site = site.clone().ok_or_else(|| error(400, "site is required"))?;
+    if state.router.handler(&site).is_none() {
+        return Err(error(400, "Unknown site"));
+    }
+    Ok((pool, site))
+}
+
+async fn ban(
+    request: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<ProxyRequest>,
+    state: web::Data<AdminState>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request, &state) {
+        return response;
+    }
+    let (pool, site) = match ban_target(&state, &path, &body.site) {
+        Ok(target) => target,
+        Err(response) => return response,
+    };
+    if !pool.ban(&site, &body.proxy) {
+        return error(404, "Unknown proxy");
+    }
+    info!("Admin banned proxy {} for {}", body.proxy, site);
+    HttpResponse::Ok().json(json!({ "status_code": 200 }))
+}
+
+async fn unban(
+    request: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<ProxyRequest>,
+    state: web::Data<AdminState>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request, &state) {
+        return response;
+    }
+    let (pool, site) = match ban_target(&state, &path, &body.site) {
+        Ok(target) => target,
+        Err(response) => return response,
+    };
+    if !pool.unban(&site, &body.proxy) {
+        return error(404, "Unknown proxy");
+    }
+    info!("Admin unbanned proxy {} for {}", body.proxy, site);
+    HttpResponse::Ok().json(json!({ "status_code": 200 }))
+}
+
+async fn unban_subnet(
+    request: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<SubnetRequest>,
+    state: web::Data<AdminState>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request, &state) {
+        return response;
+    }
+    let (pool, site) = match ban_target(&state, &path, &body.site) {
+        Ok(target) => target,
+        Err(response) => return response,
+    };
+    if !pool.unban_subnet(&site, &body.subnet) {
+        return error(404, "Subnet not banned for the site");
+    }
+    info!("Admin unbanned subnet {} for {}", body.subnet, site);
+    HttpResponse::Ok().json(json!({ "status_code": 200 }))
+}
+
+async fn drain(
+    request: HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<ProxyRequest>,
+    state: web::Data<AdminState>,
+) -> HttpResponse {
+    if let Err(response) = authorize(&request, &state) {
+        return response;
+    }
+    let pool = match pool(&state, &path) {
+        Ok(pool) => pool,
+        Err(response) => return response,
+    };
+    if !pool.drain(&body.proxy) {
+        return error(404, "Unknown proxy");
+    }
+    info!("Admin draining proxy {} of pool {}", body.proxy, path);
+    HttpResponse::Ok().json(json!({ "status_code": 200 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use tokio::sync::RwLock;
+
+    use crate::proxy_handler::ProxyHandler;
+    use crate::proxy_health::ProxyOutcome;
+    use crate::proxy_pool::{ProxyEntry, SelectionStrategy};
+    use crate::request_handler::AsyncRequestHandler;
+    use crate::site_registry::SiteConfig;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn state() -> (web::Data<AdminState>, Arc<ProxyPool>) {
+        let entries = [
+            "http://10.0.1.1:8080",
+            "http://10.0.1.2:8080",
+            "http://10.0.1.3:8080",
+        ]
+        .iter()
+        .map(|url| ProxyEntry::new(url.to_string()))
+        .collect();
+        let pool = Arc::new(ProxyPool::new(entries));
+        let mut router = SiteRouter::new();
+        router.add(
+            SiteConfig::new(
+                "property",
+                &["www.property.com.au"],
+                "https://www.property.com.au/",
+            ),
+            Arc::new(RwLock::new(AsyncRequestHandler::new(None, None))),
+        );
+        router.set_pool("default", pool.clone());
+        let state = AdminState {
+            token: TOKEN.to_string().into(),
+            router: Arc::new(router),
+        };
+        (web::Data::new(state), pool)
+    }
+
+    // Status and JSON body of a request to the admin API with the token
+    async fn call(state: &web::Data<AdminState>, request: TestRequest) -> (u16, Value) {
+        let request = request.insert_header((AUTHORIZATION, format!("Bearer {}", TOKEN)));
+        send(state, request).await
+    }
+
+    async fn send(state: &web::Data<AdminState>, request: TestRequest) -> (u16, Value) {
+        let app = test::init_service(
+            App::new().service(
+                web::scope("/admin")
+                    .app_data(state.clone())
+                    .configure(routes),
+            ),
+        )
+        .await;
+        let response = test::call_service(&app, request.to_request()).await;
+        let status = response.status().as_u16();
+        (status, test::read_body_json(response).await)
+    }
+
+    fn post(uri: &str, body: Value) -> TestRequest {
+        TestRequest::post().uri(uri).set_json(body)
+    }
+
+    #[actix_web::test]
+    async fn requests_need_the_admin_token() {
+        let (state, _) = state();
+        let (status, _) = send(&state, TestRequest::get().uri("/admin/pools")).await;
+        assert_eq!(status, 401);
+        let wrong = TestRequest::get()
+            .uri("/admin/pools")
+            .insert_header((AUTHORIZATION, "Bearer fedcba9876543210"));
+        assert_eq!(send(&state, wrong).await.0, 401);
+
+        let (status, body) = call(&state, TestRequest::get().uri("/admin/pools")).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["pools"][0]["pool"], "default");
+        assert_eq!(body["pools"][0]["proxies"].as_array().unwrap().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn unknown_pools_and_sites_are_rejected() {
+        let (state, _) = state();
+        let proxy = "http://10.0.1.1:8080/";
+        let (status, _) = call(
+            &state,
+            post(
+                "/admin/pools/other/ban",
+                json!({ "proxy": proxy, "site": "property" }),
+            ),
+        )
+        .await;
+        assert_eq!(status, 404);
+        let (status, body) = call(
+            &state,
+            post(
+                "/admin/pools/default/ban",
+                json!({ "proxy": proxy, "site": "unknown" }),
+            ),
+        )
+        .await;
+        assert_eq!((status, body["msg"].as_str()), (400, Some("Unknown site")));
+        let (status, body) = call(
+            &state,
+            post("/admin/pools/default/ban", json!({ "proxy": proxy })),
+        )
+        .await;
+        assert_eq!(
+            (status, body["msg"].as_str()),
+            (400, Some("site is required"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn proxies_are_added_banned_and_drained() {
+        let (state, pool) = state();
+        let added = json!({ "proxy": "10.0.2.1:8080 country=au", "weight": 2 });
+        let (status, body) =
+            call(&state, post("/admin/pools/default/proxies", added.clone())).await;
+        assert_eq!(
+            (status, body["proxy"].as_str()),
+            (200, Some("http://10.0.2.1:8080/"))
+        );
+        assert_eq!(
+            call(&state, post("/admin/pools/default/proxies", added))
+                .await
+                .0,
+            400
+        );
+
+        let target = json!({ "proxy": "http://10.0.2.1:8080/", "site": "property" });
+        assert_eq!(
+            call(&state, post("/admin/pools/default/ban", target.clone()))
+                .await
+                .0,
+            200
+        );
+        let (_, body) = call(&state, TestRequest::get().uri("/admin/pools")).await;
+        assert_eq!(
+            body["pools"][0]["proxies"][3]["banned"],
+            json!(["property"])
+        );
+        assert_eq!(
+            call(&state, post("/admin/pools/default/unban", target.clone()))
+                .await
+                .0,
+            200
+        );
+        assert!(pool.status()[3].banned.is_empty());
+
+        assert_eq!(
+            call(&state, post("/admin/pools/default/drain", target.clone()))
+                .await
+                .0,
+            200
+        );
+        assert_eq!(pool.len(), 3);
+        assert_eq!(
+            call(&state, post("/admin/pools/default/drain", target))
+                .await
+                .0,
+            404
+        );
+    }
+
+    #[actix_web::test]
+    async fn subnet_bans_can_be_lifted() {
+        let (state, pool) = state();
+        let mut proxies = pool.site("property", SelectionStrategy::RoundRobin);
+        for proxy in pool.urls() {
+            proxies.release(&proxy, ProxyOutcome::Blocked);
+        }
+        let (_, body) = call(&state, TestRequest::get().uri("/admin/pools")).await;
+        assert_eq!(body["pools"][0]["subnet_bans"][0]["subnet"], "10.0.1.0/24");
+
+        let subnet = json!({ "subnet": "10.0.1.0/24", "site": "property" });
+        let uri = "/admin/pools/default/subnets/unban";
+        assert_eq!(call(&state, post(uri, subnet.clone())).await.0, 200);
+        assert!(pool.subnet_bans().is_empty());
+        assert_eq!(call(&state, post(uri, subnet)).await.0, 404);
+    }
+
+    #[test]
+    fn token_must_match_exactly() {
+        assert!(token_matches("0123456789abcdef", "0123456789abcdef"));
+        assert!(!token_matches("0123456789abcdef", "0123456789abcdeg"));
+        assert!(!token_matches("0123456789abcdef", "0123456789abcde"));
+        assert!(!token_matches("0123456789abcdef", ""));
+    }
+}
@@ -0,0 +1,131 @@
+use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::time::Duration;
+
+use webunlocker::{Config, Unlocker};
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "webunlocker",
+    version,
+    about = "Proxy and cookie aware scraping API"
+)]
+pub struct Cli {
+    /// Config file to load, overrides CONFIG_FILE
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Override a config key, e.g. `--set server.port=6000`
+    #[arg(long = "set", global = true, value_name = "KEY=VALUE")]
+    pub overrides: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the HTTP API, the default when no command is given
+    Serve,
+    /// Validate the configuration and print it with secrets redacted
+    CheckConfig,
+    /// Probe every proxy of a pool against a URL
+    TestProxies {
+        /// Proxy pool to probe
+        #[arg(long, default_value = "default")]
+        pool: String,
+        /// URL requested through each proxy
+        #[arg(long, default_value = "https://www.google.com/")]
+        url: String,
+        /// Timeout of each probe
+        #[arg(long, default_value_t = 10)]
+        timeout_secs: u64,
+        /// Proxies probed at the same time
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+    },
+    /// Generate cookies for a site with its cookie provider and print them
+    GenCookies {
+        /// Site name from the config
+        site: String,
+    },
+    /// Fetch a URL once through the handler of its site and print the body
+    Fetch {
+        /// URL of one of the configured sites
+        url: String,
+    },
+}
+
+pub fn check_config(file: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    // The proxies files are parsed too so a bad line is caught before a reload
+    let unlocker = Unlocker::from_config(config)?;
+    println!("Configuration loaded from {} is valid", file);
+    println!("{:#?}", config);
+    for (name, size) in unlocker.pool_sizes() {
+        println!(
+            "Proxy pool {}: {} proxies in {}",
+            name, size, config.proxy_pools[&name].proxies_file
+        );
+    }
+    Ok(())
+}
+
+pub async fn test_proxies(
+    config: &Config,
+    pool: &str,
+    url: &str,
+    timeout_secs: u64,
+    concurrency: usize,
+) -> Result<(), Box<dyn Error>> {
+    let unlocker = Unlocker::from_config(config)?;
+    let size = unlocker
+        .pool_sizes()
+        .into_iter()
+        .find(|(name, _)| name == pool)
+        .map(|(_, size)| size)
+        .ok_or_else(|| format!("unknown proxy pool {:?}", pool))?;
+    if size == 0 {
+        return Err(format!(
+            "no proxies found in {}",
+            config.proxy_pools[pool].proxies_file
+        )
+        .into());
+    }
+    println!("Probing {} proxies of pool {} against {}", size, pool, url);
+
+    let probes = unlocker
+        .probe_pool(pool, url, Duration::from_secs(timeout_secs), concurrency)
+        .await
+        .unwrap_or_default();
+    let mut reachable = 0;
+    for probe in probes {
+        match probe.status {
+            Ok(status) => {
+                reachable += 1;
+                println!(
+                    "ok     {:<20} {} in {} ms",
+                    probe.proxy,
+                    status,
+                    probe.elapsed.as_millis()
+                );
+            }
+            Err(e) => println!("failed {:<20} {}", probe.proxy, e),
+        }
+    }
+    println!("{} proxies reachable", reachable);
+    Ok(())
+}
+
+pub async fn gen_cookies(config: &Config, site_name: &str) -> Result<(), Box<dyn Error>> {
+    let cookies = Unlocker::from_config(config)?
+        .generate_cookies(site_name)
+        .await?;
+    println!("{}", serde_json::to_string_pretty(&cookies)?);
+    Ok(())
+}
+
+pub async fn fetch(config: &Config, url: &str) -> Result<(), Box<dyn Error>> {
+    let body = Unlocker::from_config(config)?.fetch(url).await?;
+    println!("{}", body);
+    Ok(())
+}
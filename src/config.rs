@@ -1,101 +1,1046 @@
 use dotenv::dotenv;
-use log::{error, warn};
+use serde::de::{self, Deserializer};
+use serde_derive::Deserialize;
+use serde_path_to_error::Segment;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
 use std::env;
-use std::process;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
 
-#[derive(Debug)]
+use crate::proxy_handler::ProxyCredentials;
+use crate::proxy_health::{HealthPolicy, MAX_PENALTY_SECS};
+use crate::proxy_list::ProxyScheme;
+use crate::proxy_pool::SubnetPolicy;
+use crate::request_handler::RetryPolicy;
+use crate::secret::{Secret, SECRET_SCHEME};
+use crate::site_registry::{validate_sites, SiteConfig};
+
+// Prefix of the environment variables overriding any key of the config file,
+// `WEBUNLOCKER__SERVER__PORT=6000` overrides `server.port`
+const ENV_PREFIX: &str = "WEBUNLOCKER__";
+
+// Flat environment variables kept for existing deployments, with the key they override
+const LEGACY_ENV_VARS: [(&str, &str); 7] = [
+    ("PROXY_USERNAME", "proxy_pools.default.username"),
+    ("PROXY_PASSWORD", "proxy_pools.default.password"),
+    ("PROXY_HOST", "proxy_pools.default.host"),
+    ("PROXY_PORT", "proxy_pools.default.port"),
+    ("PROXY_TEXT_FILE", "proxy_pools.default.proxies_file"),
+    ("ZENROWS_API_KEY", "cookie_providers.zenrows.api_key"),
+    ("API_PORT", "server.port"),
+];
+
+// Legacy variables that can also be read from a mounted file through `<VAR>_FILE`
+const SECRET_ENV_VARS: [&str; 3] = ["PROXY_USERNAME", "PROXY_PASSWORD", "ZENROWS_API_KEY"];
+
+#[derive(Debug, Deserialize)]
 pub struct Config {
-    pub proxy_username: String,
-    pub proxy_password: String,
-    pub proxy_host: String,
-    pub proxy_port: String,
-    pub zenrows_api_key: String,
-    pub api_port: String,
-    pub proxies_txt_file: String,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
+    #[serde(default)]
+    pub proxy_pools: HashMap<String, ProxyPoolConfig>,
+    #[serde(default)]
+    pub cookie_providers: HashMap<String, CookieProviderConfig>,
+    #[serde(default)]
+    pub retry_policies: HashMap<String, RetryPolicy>,
+    #[serde(default)]
+    pub sites: Vec<SiteConfig>,
 }
 
-impl Config {
-    // Function to load and validate environment variables
-    pub fn load() -> Config {
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: String,
+    #[serde(deserialize_with = "lenient")]
+    pub port: u16,
+    #[serde(deserialize_with = "lenient")]
+    pub workers: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 5000,
+            workers: 1,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub config_file: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            config_file: "log4rs.yaml".to_string(),
+        }
+    }
+}
+
+// Watching of the config file and proxy files, a reload can also be
+// triggered with SIGHUP
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ReloadConfig {
+    #[serde(deserialize_with = "lenient")]
+    pub enabled: bool,
+    #[serde(deserialize_with = "lenient")]
+    pub poll_interval_secs: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        ReloadConfig {
+            enabled: true,
+            poll_interval_secs: 5,
+        }
+    }
+}
+
+// Admin API managing the proxy pools at runtime, disabled while no token is set
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub token: Option<Secret>,
+}
+
+// Shortest admin token accepted
+const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+// Proxy health and bans saved to a file and restored on startup, disabled
+// while no file is set
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PersistenceConfig {
+    pub file: Option<String>,
+    #[serde(deserialize_with = "lenient")]
+    pub save_interval_secs: u64,
+    // Bans older than this are dropped on restore, as is the health saved that long ago
+    #[serde(deserialize_with = "lenient")]
+    pub max_age_secs: u64,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        PersistenceConfig {
+            file: None,
+            save_interval_secs: 60,
+            max_age_secs: 86400,
+        }
+    }
+}
+
+// Background probing of every proxy, proxies failing their last probe are
+// not handed out until a later probe succeeds
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    #[serde(deserialize_with = "lenient")]
+    pub enabled: bool,
+    pub probe_url: String,
+    #[serde(deserialize_with = "lenient")]
+    pub interval_secs: u64,
+    #[serde(deserialize_with = "lenient")]
+    pub timeout_secs: u64,
+    // Probes running at the same time
+    #[serde(deserialize_with = "lenient")]
+    pub concurrency: usize,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            enabled: false,
+            probe_url: "https://www.google.com/".to_string(),
+            interval_secs: 300,
+            timeout_secs: 10,
+            concurrency: 8,
+        }
+    }
+}
+
+// Upstream proxy credentials and the file listing the proxies of the pool
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct ProxyPoolConfig {
+    pub username: Option<Secret>,
+    pub password: Option<Secret>,
+    pub host: Option<String>,
+    #[serde(default, deserialize_with = "lenient_some")]
+    pub port: Option<u16>,
+    pub zone: Option<String>,
+    // Protocol of the proxy URLs built by the provider: http, https, socks5 or
+    // socks5h. Full URLs of the proxies file keep their own scheme.
+    #[serde(default)]
+    pub scheme: ProxyScheme,
+    // How the proxies of the file are turned into proxy URLs
+    #[serde(default)]
+    pub provider: ProxyProviderConfig,
+    #[serde(default = "default_proxies_file")]
+    pub proxies_file: String,
+    // Relative weight of each proxy for the weighted strategy, 1 when not
+    // listed. Keys are normalized with `proxy_key`.
+    #[serde(default)]
+    pub weights: HashMap<String, u32>,
+    // Cooldown and quarantine of proxies failing or blocked by the target
+    #[serde(default)]
+    pub health: HealthPolicy,
+    // Spreading of the picks across subnets and bans of whole subnets
+    #[serde(default)]
+    pub subnets: SubnetPolicy,
+}
+
+fn default_proxies_file() -> String {
+    "proxies.txt".to_string()
+}
+
+impl ProxyPoolConfig {
+    // Credentials handed to the proxy handlers, only call on a validated config
+    pub fn credentials(&self) -> ProxyCredentials {
+        ProxyCredentials {
+            username: self
+                .username
+                .as_ref()
+                .map(|username| username.expose().to_string())
+                .unwrap_or_default(),
+            password: self.password.clone().unwrap_or_default(),
+            host: self.host.clone().unwrap_or_default(),
+            port: self.port.unwrap_or_default(),
+            zone: self.zone.clone().filter(|zone| !zone.is_empty()),
+            scheme: self.scheme,
+        }
+    }
+
+    // Fields the provider of the pool needs, the static provider needs none
+    pub fn missing_fields(&self) -> Vec<&'static str> {
+        let required: &[&'static str] = match self.provider {
+            ProxyProviderConfig::BrightData => &["username", "password", "host", "port"],
+            ProxyProviderConfig::Gateway { .. } => &["password", "host", "port"],
+            ProxyProviderConfig::Static => &[],
+        };
+        let fields = [
+            (
+                "username",
+                self.username.as_ref().is_some_and(|v| !v.is_empty()),
+            ),
+            (
+                "password",
+                self.password.as_ref().is_some_and(|v| !v.is_empty()),
+            ),
+            ("host", self.host.as_ref().is_some_and(|v| !v.is_empty())),
+            ("port", self.port.is_some()),
+        ];
+        fields
+            .into_iter()
+            .filter(|(field, present)| required.contains(field) && !present)
+            .map(|(field, _)| field)
+            .collect()
+    }
+}
+
+// Upstream proxy provider of a pool
+#[derive(Debug, Deserialize, PartialEq, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProxyProviderConfig {
+    // BrightData super proxy selecting each IP of the file with `-ip-<ip>`
+    #[default]
+    BrightData,
+    // Rotating gateway selecting the exit IP from a username template,
+    // with `sessions` sticky sessions added to the proxies of the file
+    Gateway {
+        username_template: String,
+        #[serde(default, deserialize_with = "lenient")]
+        sessions: u32,
+    },
+    // Proxies of the file used as listed
+    Static,
+}
+
+// Cookie provider credentials, referenced by name from the sites
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CookieProviderConfig {
+    Zenrows { api_key: Option<Secret> },
+}
+
+impl CookieProviderConfig {
+    // Credential fields that have to be set before the provider can be used
+    pub fn missing_credentials(&self) -> Vec<&'static str> {
+        match self {
+            CookieProviderConfig::Zenrows { api_key } => {
+                if api_key.as_ref().is_none_or(|v| v.is_empty()) {
+                    vec!["api_key"]
+                } else {
+                    vec![]
+                }
+            }
+        }
+    }
+}
+
+// A single problem found while loading the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(String),
+    Invalid {
+        key: String,
+        message: String,
+    },
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(key) => write!(f, "{} is missing or empty", key),
+            ConfigError::Invalid { key, message } => write!(f, "{} is invalid: {}", key, message),
+            ConfigError::Io { path, source } => write!(f, "failed to read {}: {}", path, source),
+            ConfigError::Parse { path, message } => {
+                write!(f, "failed to parse {}: {}", path, message)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+// All the problems found while loading the configuration, reported at once
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} configuration problem(s) found:", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigErrors {}
+
+impl From<ConfigError> for ConfigErrors {
+    fn from(error: ConfigError) -> Self {
+        ConfigErrors(vec![error])
+    }
+}
+
+// The layers the configuration is built from, from lowest to highest priority:
+// the config file, the environment and `key=value` overrides from the command line
+#[derive(Debug, Default, Clone)]
+pub struct ConfigSources {
+    pub file: String,
+    pub env: HashMap<String, String>,
+    pub overrides: Vec<String>,
+}
+
+impl ConfigSources {
+    // Sources from the process environment and the .env file
+    pub fn from_env() -> ConfigSources {
         // Load the .env file only once at the start
         dotenv().ok();
+        let env: HashMap<String, String> = env::vars().collect();
+        let file = match env.get("CONFIG_FILE") {
+            Some(val) if !val.is_empty() => val.clone(),
+            _ => "webunlocker.yaml".to_string(),
+        };
+        ConfigSources {
+            file,
+            env,
+            overrides: Vec::new(),
+        }
+    }
+
+    // Apply the `--config <path>` and `--set <key>=<value>` command line flags
+    pub fn with_cli(mut self, file: Option<String>, overrides: Vec<String>) -> ConfigSources {
+        if let Some(file) = file {
+            self.file = file;
+        }
+        self.overrides = overrides;
+        self
+    }
+}
+
+impl Config {
+    // Load the configuration from the config file and the process environment
+    pub fn from_env() -> Result<Config, ConfigErrors> {
+        Config::from_sources(&ConfigSources::from_env())
+    }
+
+    // Build and validate the configuration from the given layers,
+    // collecting every problem instead of stopping at the first one
+    pub fn from_sources(sources: &ConfigSources) -> Result<Config, ConfigErrors> {
+        let contents = fs::read_to_string(&sources.file).map_err(|source| ConfigError::Io {
+            path: sources.file.clone(),
+            source,
+        })?;
+        let mut root: Value = serde_yaml::from_str(&contents).map_err(|e| ConfigError::Parse {
+            path: sources.file.clone(),
+            message: e.to_string(),
+        })?;
+        if root.is_null() {
+            root = Value::Mapping(Mapping::new());
+        }
 
-        // Load and validate each environment variable
-        let proxy_username = match env::var("PROXY_USERNAME") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                error!("PROXY_USERNAME is missing or empty");
-                process::exit(1);
+        let mut errors = Vec::new();
+
+        // Environment overrides, legacy variables first so the prefixed ones win
+        let mut overrides = Vec::new();
+        for (var, key) in LEGACY_ENV_VARS {
+            match legacy_env_value(&sources.env, var) {
+                Ok(Some(value)) => overrides.push(Override::new(key, var, value)),
+                Ok(None) => continue,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
             }
-        };
+            if var == "ZENROWS_API_KEY" {
+                // The legacy key alone is enough to declare the ZenRows provider
+                let _ = set_default_key(&mut root, "cookie_providers.zenrows.kind", "zenrows");
+            }
+        }
+        let mut prefixed: Vec<_> = sources
+            .env
+            .iter()
+            .filter_map(|(var, val)| var.strip_prefix(ENV_PREFIX).map(|key| (key, var, val)))
+            .collect();
+        prefixed.sort();
+        for (key, var, val) in prefixed {
+            let key = key.to_ascii_lowercase().replace("__", ".");
+            overrides.push(Override::new(&key, var, val.clone()));
+        }
 
-        let proxy_password = match env::var("PROXY_PASSWORD") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                error!("PROXY_PASSWORD is missing or empty");
-                process::exit(1);
+        // Command line overrides
+        for item in &sources.overrides {
+            match item.split_once('=') {
+                Some((key, val)) => {
+                    overrides.push(Override::new(key.trim(), key.trim(), val.to_string()))
+                }
+                None => errors.push(ConfigError::Invalid {
+                    key: item.clone(),
+                    message: "expected <key>=<value>".to_string(),
+                }),
             }
-        };
+        }
 
-        let proxy_host = match env::var("PROXY_HOST") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                error!("PROXY_HOST is missing or empty");
-                process::exit(1);
+        // Overrides that cannot be placed in the tree are reported and skipped
+        let mut layered = root.clone();
+        overrides.retain(|o| match set_key(&mut layered, &o.key, &o.value) {
+            Ok(()) => true,
+            Err(e) => {
+                errors.push(e);
+                false
             }
+        });
+
+        let Some(mut config) = deserialize_layers(&sources.file, root, overrides, &mut errors)
+        else {
+            return Err(ConfigErrors(errors));
         };
+        config
+            .retry_policies
+            .entry("default".to_string())
+            .or_default();
+
+        errors.extend(config.validate());
+        if !errors.is_empty() {
+            return Err(ConfigErrors(errors));
+        }
+
+        // Return the Config instance
+        Ok(config)
+    }
 
-        let proxy_port = match env::var("PROXY_PORT") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                error!("PROXY_PORT is missing or empty");
-                process::exit(1);
+    fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+
+        for (name, pool) in &self.proxy_pools {
+            for field in pool.missing_fields() {
+                errors.push(ConfigError::Missing(format!(
+                    "proxy_pools.{}.{}",
+                    name, field
+                )));
             }
-        };
+            if let ProxyProviderConfig::Gateway {
+                username_template, ..
+            } = &pool.provider
+            {
+                if username_template.is_empty() {
+                    errors.push(ConfigError::Missing(format!(
+                        "proxy_pools.{}.provider.username_template",
+                        name
+                    )));
+                }
+            }
+            let penalties = [
+                ("health.base_cooldown_secs", pool.health.base_cooldown_secs),
+                ("health.max_cooldown_secs", pool.health.max_cooldown_secs),
+                ("health.quarantine_secs", pool.health.quarantine_secs),
+                ("subnets.ban_secs", pool.subnets.ban_secs),
+            ];
+            for (field, secs) in penalties {
+                if secs > MAX_PENALTY_SECS {
+                    errors.push(ConfigError::Invalid {
+                        key: format!("proxy_pools.{}.{}", name, field),
+                        message: format!("must be at most {}", MAX_PENALTY_SECS),
+                    });
+                }
+            }
+            if pool.subnets.ban_after > 0 && pool.subnets.window_secs == 0 {
+                errors.push(ConfigError::Invalid {
+                    key: format!("proxy_pools.{}.subnets.window_secs", name),
+                    message: "must be greater than 0 when ban_after is set".to_string(),
+                });
+            }
+        }
 
-        let zenrows_api_key = match env::var("ZENROWS_API_KEY") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                error!("ZENROWS_API_KEY is missing or empty");
-                process::exit(1);
+        // Provider credentials are only required when a site uses the provider
+        let mut used_providers: Vec<&String> = self
+            .sites
+            .iter()
+            .filter_map(|site| site.cookie_provider.as_ref())
+            .map(|cookies| &cookies.provider)
+            .collect();
+        used_providers.sort();
+        used_providers.dedup();
+        for name in used_providers {
+            if let Some(provider) = self.cookie_providers.get(name) {
+                for field in provider.missing_credentials() {
+                    errors.push(ConfigError::Missing(format!(
+                        "cookie_providers.{}.{}",
+                        name, field
+                    )));
+                }
             }
-        };
-        let api_port = match env::var("API_PORT") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                warn!("API start running on default port 5000");
-                "5000".to_string()
+        }
+
+        if self.reload.enabled && self.reload.poll_interval_secs == 0 {
+            errors.push(ConfigError::Invalid {
+                key: "reload.poll_interval_secs".to_string(),
+                message: "must be greater than 0".to_string(),
+            });
+        }
+        if let Some(ref token) = self.admin.token {
+            if token.expose().len() < MIN_ADMIN_TOKEN_LEN {
+                errors.push(ConfigError::Invalid {
+                    key: "admin.token".to_string(),
+                    message: format!("must be at least {} characters", MIN_ADMIN_TOKEN_LEN),
+                });
             }
-        };
-        let proxies_txt_file = match env::var("PROXY_TEXT_FILE") {
-            Ok(val) if !val.is_empty() => val,
-            _ => {
-                warn!("API start running on default proxy text file");
-                "proxies.txt".to_string()
+        }
+        if self.persistence.file.is_some() {
+            for (key, value) in [
+                ("save_interval_secs", self.persistence.save_interval_secs),
+                ("max_age_secs", self.persistence.max_age_secs),
+            ] {
+                if value == 0 {
+                    errors.push(ConfigError::Invalid {
+                        key: format!("persistence.{}", key),
+                        message: "must be greater than 0".to_string(),
+                    });
+                }
             }
+        }
+        if self.health_check.enabled {
+            for (key, value) in [
+                ("interval_secs", self.health_check.interval_secs),
+                ("timeout_secs", self.health_check.timeout_secs),
+                ("concurrency", self.health_check.concurrency as u64),
+            ] {
+                if value == 0 {
+                    errors.push(ConfigError::Invalid {
+                        key: format!("health_check.{}", key),
+                        message: "must be greater than 0".to_string(),
+                    });
+                }
+            }
+        }
+
+        errors.extend(validate_sites(self));
+        errors
+    }
+}
+
+// A value of the environment or the command line replacing a key of the config file
+struct Override {
+    key: String,
+    // Variable or command line key the value came from, named in errors
+    source: String,
+    value: String,
+}
+
+impl Override {
+    fn new(key: &str, source: &str, value: String) -> Self {
+        Override {
+            key: key.to_string(),
+            source: source.to_string(),
+            value,
+        }
+    }
+
+    // Whether a deserialization error at the given key comes from this value
+    fn covers(&self, path: &str) -> bool {
+        path == self.key
+            || path.starts_with(&format!("{}.", self.key))
+            || (!path.is_empty() && self.key.starts_with(&format!("{}.", path)))
+    }
+}
+
+// Deserialize the config file with the overrides applied. An override that
+// does not fit its key is reported under the variable it came from and left
+// out, and the rest are deserialized again so every bad value gets reported.
+fn deserialize_layers(
+    file: &str,
+    root: Value,
+    overrides: Vec<Override>,
+    errors: &mut Vec<ConfigError>,
+) -> Option<Config> {
+    let mut applied = overrides;
+    loop {
+        let mut layered = root.clone();
+        for o in &applied {
+            // Every override was already placed once, it cannot fail
+            let _ = set_key(&mut layered, &o.key, &o.value);
+        }
+        let e = match serde_path_to_error::deserialize::<_, Config>(layered) {
+            Ok(config) => return Some(config),
+            Err(e) => e,
         };
+        let path = key_path(e.path());
+        let message = e.into_inner().to_string();
+        // The last override of a key is the one in effect
+        match applied.iter().rposition(|o| o.covers(&path)) {
+            Some(index) => {
+                let o = applied.remove(index);
+                errors.push(ConfigError::Invalid {
+                    key: o.source,
+                    message,
+                });
+            }
+            None => {
+                errors.push(ConfigError::Parse {
+                    path: file.to_string(),
+                    message: match path.as_str() {
+                        "" => message,
+                        _ => format!("{}: {}", path, message),
+                    },
+                });
+                return None;
+            }
+        }
+    }
+}
 
-        // Return the Config instance
-        Config {
-            proxy_username,
-            proxy_password,
-            proxy_host,
-            proxy_port,
-            zenrows_api_key,
-            api_port,
-            proxies_txt_file,
+// Dotted key of a deserialization error, in the format of the overrides
+fn key_path(path: &serde_path_to_error::Path) -> String {
+    path.iter()
+        .filter_map(|segment| match segment {
+            Segment::Seq { index } => Some(index.to_string()),
+            Segment::Map { key } => Some(key.clone()),
+            Segment::Enum { .. } | Segment::Unknown => None,
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+// Value of a legacy variable, either set directly or through `<VAR>_FILE`
+// in which case it is resolved like a `secret://` value of the config file
+fn legacy_env_value(
+    env: &HashMap<String, String>,
+    var: &str,
+) -> Result<Option<String>, ConfigError> {
+    let direct = env.get(var).filter(|val| !val.is_empty());
+    let file_var = format!("{}_FILE", var);
+    let file = env
+        .get(&file_var)
+        .filter(|path| !path.is_empty() && SECRET_ENV_VARS.contains(&var));
+
+    match (direct, file) {
+        (Some(_), Some(_)) => Err(ConfigError::Invalid {
+            key: var.to_string(),
+            message: format!("both {} and {} are set", var, file_var),
+        }),
+        (Some(val), None) => Ok(Some(val.clone())),
+        (None, Some(path)) => Ok(Some(format!("{}{}", SECRET_SCHEME, path))),
+        (None, None) => Ok(None),
+    }
+}
+
+// Set a dotted key like `proxy_pools.default.host` inside the YAML tree,
+// creating the intermediate mappings. Sequence items are addressed by index.
+fn set_key(root: &mut Value, key: &str, val: &str) -> Result<(), ConfigError> {
+    let invalid = |message: &str| ConfigError::Invalid {
+        key: key.to_string(),
+        message: message.to_string(),
+    };
+
+    let mut node = root;
+    for segment in key.split('.') {
+        if segment.is_empty() {
+            return Err(invalid("empty key segment"));
+        }
+        if node.is_null() {
+            *node = Value::Mapping(Mapping::new());
         }
+        node = match node {
+            Value::Mapping(map) => map
+                .entry(Value::String(segment.to_string()))
+                .or_insert(Value::Null),
+            Value::Sequence(items) => match segment.parse::<usize>() {
+                Ok(index) if index < items.len() => &mut items[index],
+                _ => return Err(invalid("no such list item")),
+            },
+            _ => return Err(invalid("cannot override inside a plain value")),
+        };
+    }
+    *node = Value::String(val.to_string());
+    Ok(())
+}
+
+fn set_default_key(root: &mut Value, key: &str, val: &str) -> Result<(), ConfigError> {
+    let present = key
+        .split('.')
+        .try_fold(&*root, |node, segment| node.get(segment))
+        .is_some();
+    if present {
+        return Ok(());
+    }
+    set_key(root, key, val)
+}
+
+// Accept either a native YAML value or its string form, since the values
+// coming from the environment and the command line are always strings
+pub(crate) fn lenient<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: de::Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Lenient<T> {
+        Native(T),
+        Text(String),
+    }
+
+    match <Lenient<T> as de::Deserialize>::deserialize(deserializer)? {
+        Lenient::Native(val) => Ok(val),
+        Lenient::Text(text) => text.parse().map_err(de::Error::custom),
     }
 }
 
-// fn main() {
-//     // Load the environment variables and perform strict validation
-//     let config = Config::load();
+pub(crate) fn lenient_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: de::Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    lenient(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_pool::SelectionStrategy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    // Write a file in the temp directory, returning its path
+    fn temp_file(contents: &str) -> String {
+        let file = env::temp_dir().join(format!(
+            "webunlocker-config-{}-{}",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&file, contents).unwrap();
+        file.to_string_lossy().to_string()
+    }
+
+    // Load a config file with the given contents, environment and overrides
+    fn load(yaml: &str, env: &[(&str, &str)], overrides: &[&str]) -> Result<Config, ConfigErrors> {
+        let file = temp_file(yaml);
+        let sources = ConfigSources {
+            file: file.clone(),
+            env: env
+                .iter()
+                .map(|(var, val)| (var.to_string(), val.to_string()))
+                .collect(),
+            overrides: overrides.iter().map(|item| item.to_string()).collect(),
+        };
+        let config = Config::from_sources(&sources);
+        fs::remove_file(&file).unwrap();
+        config
+    }
+
+    fn messages(errors: ConfigErrors) -> Vec<String> {
+        errors.0.iter().map(|error| error.to_string()).collect()
+    }
+
+    #[test]
+    fn every_invalid_override_is_reported_by_name() {
+        let errors = load(
+            "proxy_pools:\n  default:\n    username: customer\n",
+            &[("PROXY_PORT", "abc"), ("API_PORT", "99999")],
+            &["health_check.concurrency=many"],
+        )
+        .unwrap_err();
+        let messages = messages(errors);
+        for expected in [
+            "PROXY_PORT is invalid: invalid digit found in string",
+            "API_PORT is invalid: number too large to fit in target type",
+            "health_check.concurrency is invalid: invalid digit found in string",
+        ] {
+            assert!(
+                messages.iter().any(|message| message == expected),
+                "{:?} not in {:?}",
+                expected,
+                messages
+            );
+        }
+        // The config is still validated without the rejected values
+        assert!(messages.contains(&"proxy_pools.default.host is missing or empty".to_string()));
+    }
+
+    const CONFIG: &str = "\
+proxy_pools:
+  default:
+    username: customer
+    password: secret
+    host: brd.superproxy.io
+    port: 22225
+cookie_providers:
+  zenrows:
+    kind: zenrows
+sites:
+  - name: property
+    hosts: [www.property.com.au]
+    cookie_url: \"https://www.property.com.au/\"
+  - name: realestate
+    hosts: [www.realestate.com.au]
+    cookie_url: \"https://www.realestate.com.au/\"
+";
+
+    #[test]
+    fn loads_the_config_file() {
+        let config = load(CONFIG, &[], &[]).unwrap();
+        assert_eq!(config.server.port, 5000);
+        assert_eq!(
+            config.proxy_pools["default"].host.as_deref(),
+            Some("brd.superproxy.io")
+        );
+        assert_eq!(config.sites.len(), 2);
+        assert!(config.retry_policies.contains_key("default"));
 
-//     // Log loaded configuration values
-//     info!("Proxy Username: {}", config.proxy_username);
-//     info!("Proxy Password: {}", config.proxy_password);
-//     info!("Proxy Host: {}", config.proxy_host);
-//     info!("Proxy Port: {}", config.proxy_port);
-//     info!("ZenRows API Key: {}", config.zenrows_api_key);
-// }
+        let errors = Config::from_sources(&ConfigSources {
+            file: "missing-webunlocker.yaml".to_string(),
+            ..ConfigSources::default()
+        })
+        .unwrap_err();
+        assert!(matches!(errors.0[..], [ConfigError::Io { .. }]));
+    }
+
+    #[test]
+    fn reports_every_missing_credential() {
+        let errors = load("proxy_pools:\n  default: {}\n", &[], &[]).unwrap_err();
+        let mut messages = messages(errors);
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                "proxy_pools.default.host is missing or empty",
+                "proxy_pools.default.password is missing or empty",
+                "proxy_pools.default.port is missing or empty",
+                "proxy_pools.default.username is missing or empty",
+                "sites is invalid: no sites defined",
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixed_variables_win_over_legacy_ones_and_the_command_line_over_both() {
+        let env = [
+            ("PROXY_HOST", "legacy.example.com"),
+            (
+                "WEBUNLOCKER__PROXY_POOLS__DEFAULT__HOST",
+                "prefixed.example.com",
+            ),
+            ("PROXY_USERNAME", "legacy"),
+        ];
+        let config = load(CONFIG, &env, &[]).unwrap();
+        let pool = &config.proxy_pools["default"];
+        assert_eq!(pool.host.as_deref(), Some("prefixed.example.com"));
+        assert_eq!(pool.username.as_ref().unwrap().expose(), "legacy");
+
+        let config = load(CONFIG, &env, &["proxy_pools.default.host=cli.example.com"]).unwrap();
+        assert_eq!(
+            config.proxy_pools["default"].host.as_deref(),
+            Some("cli.example.com")
+        );
+    }
+
+    #[test]
+    fn string_overrides_are_parsed_into_typed_values() {
+        let env = [
+            ("API_PORT", "6000"),
+            ("WEBUNLOCKER__SITES__1__PROXY_STRATEGY", "round_robin"),
+        ];
+        let config = load(
+            CONFIG,
+            &env,
+            &["sites.0.direct=true", "reload.enabled=false"],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 6000);
+        assert!(config.sites[0].direct);
+        assert_eq!(
+            config.sites[1].proxy_strategy,
+            SelectionStrategy::RoundRobin
+        );
+        assert!(!config.reload.enabled);
+
+        let errors = load(CONFIG, &[], &["sites.5.direct=true", "server"]).unwrap_err();
+        assert_eq!(
+            messages(errors),
+            [
+                "server is invalid: expected <key>=<value>",
+                "sites.5.direct is invalid: no such list item",
+            ]
+        );
+    }
+
+    #[test]
+    fn set_key_builds_the_path() {
+        let mut root: Value = serde_yaml::from_str("server:\n  port: 5000\n").unwrap();
+        set_key(&mut root, "proxy_pools.default.host", "example.com").unwrap();
+        assert_eq!(root["proxy_pools"]["default"]["host"], "example.com");
+        assert!(set_key(&mut root, "server.port.inner", "1").is_err());
+        assert!(set_key(&mut root, "server..port", "1").is_err());
+    }
+
+    #[test]
+    fn penalties_longer_than_a_year_are_rejected() {
+        let errors = load(
+            CONFIG,
+            &[],
+            &[
+                "proxy_pools.default.health.quarantine_secs=18446744073709551615",
+                "proxy_pools.default.health.max_cooldown_secs=31536000",
+                "proxy_pools.default.subnets.ban_secs=31536001",
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            messages(errors),
+            [
+                "proxy_pools.default.health.quarantine_secs is invalid: must be at most 31536000",
+                "proxy_pools.default.subnets.ban_secs is invalid: must be at most 31536000"
+            ]
+        );
+    }
+
+    #[test]
+    fn cookie_credentials_are_only_required_for_used_providers() {
+        assert!(load(CONFIG, &[], &[]).is_ok());
+
+        let used = ["sites.0.cookie_provider.provider=zenrows"];
+        let errors = load(CONFIG, &[], &used).unwrap_err();
+        assert_eq!(
+            messages(errors),
+            ["cookie_providers.zenrows.api_key is missing or empty"]
+        );
+        assert!(load(CONFIG, &[("ZENROWS_API_KEY", "key")], &used).is_ok());
+    }
+
+    #[test]
+    fn secrets_are_read_from_files() {
+        let password = temp_file("file-password\n");
+        let api_key = temp_file("file-api-key");
+        let username = format!("proxy_pools.default.username={}{}", SECRET_SCHEME, api_key);
+        let config = load(
+            CONFIG,
+            &[
+                ("PROXY_PASSWORD_FILE", &password),
+                ("ZENROWS_API_KEY_FILE", &api_key),
+            ],
+            &[&username],
+        )
+        .unwrap();
+        let pool = &config.proxy_pools["default"];
+        assert_eq!(pool.password.as_ref().unwrap().expose(), "file-password");
+        assert_eq!(pool.username.as_ref().unwrap().expose(), "file-api-key");
+        let CookieProviderConfig::Zenrows { api_key: key } = &config.cookie_providers["zenrows"];
+        assert_eq!(key.as_ref().unwrap().expose(), "file-api-key");
+
+        let errors = load(
+            CONFIG,
+            &[
+                ("PROXY_PASSWORD", "direct"),
+                ("PROXY_PASSWORD_FILE", &password),
+            ],
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(
+            messages(errors),
+            ["PROXY_PASSWORD is invalid: both PROXY_PASSWORD and PROXY_PASSWORD_FILE are set"]
+        );
+
+        let errors =
+            load(CONFIG, &[("PROXY_PASSWORD_FILE", "/missing/password")], &[]).unwrap_err();
+        assert!(messages(errors)[0].starts_with(
+            "PROXY_PASSWORD is invalid: failed to read secret file /missing/password"
+        ));
+        fs::remove_file(password).unwrap();
+        fs::remove_file(api_key).unwrap();
+    }
+
+    #[test]
+    fn debug_output_hides_secrets() {
+        let api_key = temp_file("zenrows-key-from-file");
+        let config = load(
+            CONFIG,
+            &[
+                ("PROXY_PASSWORD", "proxy-password-value"),
+                ("ZENROWS_API_KEY_FILE", &api_key),
+            ],
+            &["admin.token=admin-token-value-0123456789"],
+        )
+        .unwrap();
+        let output = format!("{:?}", config);
+        for secret in [
+            "proxy-password-value",
+            "zenrows-key-from-file",
+            "admin-token-value-0123456789",
+        ] {
+            assert!(!output.contains(secret), "{} leaked in {}", secret, output);
+        }
+        assert!(output.contains("[redacted]"));
+        fs::remove_file(api_key).unwrap();
+    }
+}
@@ -3,7 +3,7 @@ use log::{error, info};
 use reqwest::Error as ReqwestError;
 use reqwest::{
     header::{HeaderMap, HeaderValue, ACCEPT, COOKIE, USER_AGENT},
-    Client, Proxy,
+    Client,
 };
 
 use std::error::Error;
@@ -11,7 +11,10 @@ use std::fmt;
 
 use std::collections::HashMap;
 
-use crate::proxy_handler::ProxyHandler;
+use crate::proxy_handler::{
+    proxy_client, ProxyContext, ProxyError, ProxyTargeting, SharedAsyncProxyHandler,
+};
+use crate::proxy_health::ProxyOutcome;
 
 #[derive(Debug)]
 pub struct CookieException {
@@ -35,6 +38,14 @@ impl From<ReqwestError> for CookieException {
     }
 }
 
+impl From<ProxyError> for CookieException {
+    fn from(error: ProxyError) -> Self {
+        CookieException {
+            message: error.to_string(),
+        }
+    }
+}
+
 #[async_trait]
 pub trait BaseCookiesHandler {
     async fn generate(&self) -> Result<HashMap<String, String>, CookieException>;
@@ -46,7 +57,10 @@ pub struct ZenrowsCookiesHandler {
     cookie_url: String,
     api_key: String,
     premium_proxy: bool,
-    proxy_handler: Option<Box<dyn ProxyHandler>>,
+    proxy_handler: Option<SharedAsyncProxyHandler>,
+    // Targeting of the site, cookies are validated from the same kind of exit
+    // as the requests using them
+    targeting: ProxyTargeting,
 }
 
 impl ZenrowsCookiesHandler {
@@ -54,16 +68,22 @@ impl ZenrowsCookiesHandler {
         cookie_url: String,
         api_key: String,
         premium_proxy: bool,
-        proxy_handler: Option<Box<dyn ProxyHandler>>,
+        proxy_handler: Option<SharedAsyncProxyHandler>,
     ) -> Self {
         ZenrowsCookiesHandler {
             cookie_url,
             api_key,
             premium_proxy,
             proxy_handler,
+            targeting: ProxyTargeting::default(),
         }
     }
 
+    pub fn with_targeting(mut self, targeting: ProxyTargeting) -> Self {
+        self.targeting = targeting;
+        self
+    }
+
     fn parse_cookies(cookie_string: &str) -> HashMap<String, String> {
         let mut cookie_map = HashMap::new();
         for item in cookie_string.split(';') {
@@ -149,14 +169,35 @@ impl BaseCookiesHandler for ZenrowsCookiesHandler {
             headers
         );
 
-        let proxy = Proxy::all(self.proxy_handler.as_ref().unwrap().get_proxy().unwrap())?;
-
-        let client = Client::builder().proxy(proxy).build()?;
-        let res = client
-            .get(self.cookie_url.clone())
-            .headers(headers)
-            .send()
-            .await?;
+        // Validated through the proxies of the site, or directly for sites without
+        let lease = match self.proxy_handler {
+            Some(ref proxy_handler) => {
+                let ctx = ProxyContext {
+                    url: self.cookie_url.clone(),
+                    targeting: self.targeting.clone(),
+                };
+                Some(proxy_handler.acquire(&ctx).await?)
+            }
+            None => None,
+        };
+        let res = match proxy_client(lease.as_ref().map(|lease| lease.url.as_str())) {
+            Ok(client) => {
+                client
+                    .get(self.cookie_url.clone())
+                    .headers(headers)
+                    .send()
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let (Some(lease), Some(proxy_handler)) = (lease, &self.proxy_handler) {
+            let outcome = match res {
+                Ok(ref res) => ProxyOutcome::from_status(res.status().as_u16()),
+                Err(_) => ProxyOutcome::Failed,
+            };
+            proxy_handler.release(lease, outcome).await;
+        }
+        let res = res?;
 
         if res.status().is_success() {
             let body = res.text().await?;
@@ -0,0 +1,56 @@
+//! Proxy and cookie aware fetching of protected sites.
+//!
+//! Build an [`Unlocker`] from a [`Config`] or with [`Unlocker::builder`], then
+//! call [`Unlocker::fetch`]. The HTTP API lives behind the `server` feature.
+
+#[cfg(feature = "server")]
+pub(crate) mod admin;
+pub(crate) mod config;
+pub(crate) mod cookies_handler;
+pub(crate) mod proxy_checker;
+pub(crate) mod proxy_handler;
+pub(crate) mod proxy_health;
+pub(crate) mod proxy_latency;
+pub(crate) mod proxy_list;
+pub(crate) mod proxy_pool;
+pub(crate) mod proxy_provider;
+pub(crate) mod proxy_state;
+pub(crate) mod rate_limit;
+#[cfg(feature = "server")]
+pub(crate) mod reload;
+pub(crate) mod request_handler;
+pub(crate) mod secret;
+#[cfg(feature = "server")]
+pub(crate) mod server;
+pub(crate) mod site_registry;
+pub(crate) mod site_router;
+pub(crate) mod unlocker;
+pub(crate) mod utils;
+
+// The stable public API, the modules themselves are internal
+pub use config::{
+    AdminConfig, Config, ConfigError, ConfigErrors, ConfigSources, CookieProviderConfig,
+    HealthCheckConfig, LoggingConfig, PersistenceConfig, ProxyPoolConfig, ProxyProviderConfig,
+    ReloadConfig, ServerConfig,
+};
+pub use cookies_handler::{BaseCookiesHandler, CookieException, ZenrowsCookiesHandler};
+pub use proxy_checker::{ProbeResult, ProxyProbe};
+pub use proxy_handler::{
+    AsyncProxyHandler, BrightDataRandomProxyHandler, ProxyContext, ProxyCredentials, ProxyError,
+    ProxyHandler, ProxyLease, ProxyTargeting, SharedAsyncProxyHandler, SharedProxyHandler,
+    SyncProxyAdapter,
+};
+pub use proxy_health::{HealthPolicy, HealthState, ProxyHealth, ProxyOutcome};
+pub use proxy_latency::{LatencyHistogram, ProxyLatency, RequestTimings};
+pub use proxy_list::{ProxyAddress, ProxyScheme, ProxySpec};
+pub use proxy_pool::{
+    ProxyEntry, ProxyPool, ProxyStatus, SelectionStrategy, SiteProxies, SubnetPolicy,
+};
+pub use proxy_provider::{BrightDataProvider, GatewayProvider, ProxyProvider, StaticProvider};
+pub use rate_limit::RateLimit;
+pub use request_handler::{AsyncRequestHandler, RetryPolicy};
+pub use secret::Secret;
+#[cfg(feature = "server")]
+pub use server::serve;
+pub use site_registry::{SiteConfig, SiteCookieConfig};
+pub use unlocker::{Unlocker, UnlockerBuilder, UnlockerError};
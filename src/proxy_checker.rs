@@ -0,0 +1,142 @@
+use log::{info, warn};
+use reqwest::{Client, Proxy};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::config::HealthCheckConfig;
+use crate::proxy_list::redact_proxy_url;
+use crate::proxy_pool::ProxyPool;
+use crate::site_router::SiteRouter;
+
+// Result of the last background probe of a proxy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeResult {
+    pub reachable: bool,
+    pub latency: Duration,
+    pub checked_at: Instant,
+}
+
+// Status code of a GET request to `url` through the proxy
+pub async fn probe(
+    proxy_url: &str,
+    url: &str,
+    timeout: Duration,
+) -> Result<u16, Box<dyn Error + Send + Sync>> {
+    let client = Client::builder()
+        .proxy(Proxy::all(proxy_url)?)
+        .timeout(timeout)
+        .build()?;
+    let response = client.get(url).send().await?;
+    Ok(response.status().as_u16())
+}
+
+// Probe of a proxy requested on demand, e.g. by `webunlocker test-proxies`
+#[derive(Debug, Clone)]
+pub struct ProxyProbe {
+    // The proxy URL without its password
+    pub proxy: String,
+    // Status code of the response, or why there was none
+    pub status: Result<u16, String>,
+    pub elapsed: Duration,
+}
+
+// Probe the given proxies against a URL, at most `concurrency` at a time,
+// results in the order of the proxies
+pub async fn probe_proxies(
+    proxy_urls: Vec<String>,
+    url: &str,
+    timeout: Duration,
+    concurrency: usize,
+) -> Vec<ProxyProbe> {
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut probes = JoinSet::new();
+    for (index, proxy_url) in proxy_urls.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        let url = url.to_string();
+        probes.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            let started = Instant::now();
+            let status = probe(&proxy_url, &url, timeout)
+                .await
+                .map_err(|e| e.to_string());
+            let result = ProxyProbe {
+                proxy: redact_proxy_url(&proxy_url),
+                status,
+                elapsed: started.elapsed(),
+            };
+            (index, result)
+        });
+    }
+    let mut results = probes.join_all().await;
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// A proxy answering 407 or a 5xx is as good as unreachable, that is how
+// gateways report an exit IP they cannot use
+fn is_reachable(status: u16) -> bool {
+    status != 407 && status < 500
+}
+
+// Probe every proxy of the pool and record the results in it
+pub async fn check_pool(pool: &ProxyPool, config: &HealthCheckConfig) {
+    let semaphore = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let mut probes = JoinSet::new();
+    for proxy_url in pool.urls() {
+        let semaphore = semaphore.clone();
+        let probe_url = config.probe_url.clone();
+        probes.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            let started = Instant::now();
+            let reachable = match probe(&proxy_url, &probe_url, timeout).await {
+                Ok(status) => is_reachable(status),
+                Err(_) => false,
+            };
+            (
+                proxy_url,
+                ProbeResult {
+                    reachable,
+                    latency: started.elapsed(),
+                    checked_at: Instant::now(),
+                },
+            )
+        });
+    }
+
+    let mut unreachable = 0;
+    for (proxy_url, result) in probes.join_all().await {
+        if !result.reachable {
+            unreachable += 1;
+        }
+        pool.record_probe(&proxy_url, result);
+    }
+    if unreachable > 0 {
+        warn!(
+            "{} of {} proxies failed their health check",
+            unreachable,
+            pool.len()
+        );
+    }
+}
+
+// Start the background task probing the proxies of every pool of the router
+pub fn spawn_checker(router: Arc<SiteRouter>, config: HealthCheckConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        loop {
+            ticker.tick().await;
+            for (name, pool) in router.pools() {
+                info!(
+                    "Health checking the {} proxies of pool {}",
+                    pool.len(),
+                    name
+                );
+                check_pool(&pool, &config).await;
+            }
+        }
+    });
+}
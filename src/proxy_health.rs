@@ -0,0 +1,239 @@
+use serde_derive::Deserialize;
+use std::time::{Duration, Instant};
+
+use crate::config::lenient;
+
+// Result of a request made through a proxy, reported when the proxy is released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyOutcome {
+    Success,
+    // The target refused the proxy (403)
+    Blocked,
+    // Connection error or unexpected status
+    Failed,
+    // Rate limited by the target (429), usually a cookie problem rather than a proxy one
+    Throttled,
+}
+
+impl ProxyOutcome {
+    // Outcome of a request answered with the given HTTP status
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            200..=299 => ProxyOutcome::Success,
+            403 => ProxyOutcome::Blocked,
+            429 => ProxyOutcome::Throttled,
+            _ => ProxyOutcome::Failed,
+        }
+    }
+}
+
+// Cooldown and quarantine settings of a proxy pool
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct HealthPolicy {
+    // Cooldown after the first failure, doubled on each consecutive failure
+    #[serde(deserialize_with = "lenient")]
+    pub base_cooldown_secs: u64,
+    #[serde(deserialize_with = "lenient")]
+    pub max_cooldown_secs: u64,
+    // Consecutive failures after which the proxy is quarantined
+    #[serde(deserialize_with = "lenient")]
+    pub quarantine_after: u32,
+    #[serde(deserialize_with = "lenient")]
+    pub quarantine_secs: u64,
+    // Successes needed after a cooldown or quarantine before the proxy is healthy again
+    #[serde(deserialize_with = "lenient")]
+    pub probation_successes: u32,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        HealthPolicy {
+            base_cooldown_secs: 30,
+            max_cooldown_secs: 1800,
+            quarantine_after: 5,
+            quarantine_secs: 3600,
+            probation_successes: 3,
+        }
+    }
+}
+
+// Longest cooldown, quarantine or ban, longer ones are capped so their end
+// always fits in an Instant
+pub const MAX_PENALTY_SECS: u64 = 365 * 24 * 3600;
+
+// End of a cooldown, quarantine or ban of `secs` starting now
+pub fn penalty_end(now: Instant, secs: u64) -> Instant {
+    now + Duration::from_secs(secs.min(MAX_PENALTY_SECS))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    Healthy,
+    CoolingDown { until: Instant },
+    Quarantined { until: Instant },
+    // Re-admitted after a cooldown or quarantine, any failure sends it to quarantine
+    Probation { successes: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyHealth {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub state: HealthState,
+}
+
+impl Default for ProxyHealth {
+    fn default() -> Self {
+        ProxyHealth {
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            state: HealthState::Healthy,
+        }
+    }
+}
+
+impl ProxyHealth {
+    pub fn record(&mut self, outcome: ProxyOutcome, policy: &HealthPolicy, now: Instant) {
+        match outcome {
+            ProxyOutcome::Success => {
+                self.successes += 1;
+                self.consecutive_failures = 0;
+                if let HealthState::Probation { successes } = self.state {
+                    self.state = if successes + 1 >= policy.probation_successes {
+                        HealthState::Healthy
+                    } else {
+                        HealthState::Probation {
+                            successes: successes + 1,
+                        }
+                    };
+                }
+            }
+            ProxyOutcome::Blocked | ProxyOutcome::Failed => {
+                self.failures += 1;
+                self.consecutive_failures += 1;
+                let on_probation = matches!(self.state, HealthState::Probation { .. });
+                self.state = if on_probation || self.consecutive_failures >= policy.quarantine_after
+                {
+                    HealthState::Quarantined {
+                        until: penalty_end(now, policy.quarantine_secs),
+                    }
+                } else {
+                    HealthState::CoolingDown {
+                        until: penalty_end(now, cooldown_secs(policy, self.consecutive_failures)),
+                    }
+                };
+            }
+            ProxyOutcome::Throttled => {}
+        }
+    }
+
+    // Whether the proxy can be handed out, moving it to probation once its
+    // cooldown or quarantine is over
+    pub fn is_available(&mut self, now: Instant) -> bool {
+        match self.state {
+            HealthState::Healthy | HealthState::Probation { .. } => true,
+            HealthState::CoolingDown { until } | HealthState::Quarantined { until } => {
+                if now >= until {
+                    self.state = HealthState::Probation { successes: 0 };
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+// Exponential cooldown: base, 2 * base, 4 * base... capped at max_cooldown_secs
+fn cooldown_secs(policy: &HealthPolicy, consecutive_failures: u32) -> u64 {
+    let factor = 2u64.saturating_pow(consecutive_failures.saturating_sub(1));
+    policy
+        .base_cooldown_secs
+        .saturating_mul(factor)
+        .min(policy.max_cooldown_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cooldown_doubles_then_quarantines() {
+        let policy = HealthPolicy::default();
+        let now = Instant::now();
+        let mut health = ProxyHealth::default();
+
+        health.record(ProxyOutcome::Blocked, &policy, now);
+        assert_eq!(
+            health.state,
+            HealthState::CoolingDown {
+                until: now + Duration::from_secs(30)
+            }
+        );
+        health.record(ProxyOutcome::Blocked, &policy, now);
+        assert_eq!(
+            health.state,
+            HealthState::CoolingDown {
+                until: now + Duration::from_secs(60)
+            }
+        );
+        for _ in 0..3 {
+            health.record(ProxyOutcome::Failed, &policy, now);
+        }
+        assert!(matches!(health.state, HealthState::Quarantined { .. }));
+        assert!(!health.is_available(now));
+    }
+
+    #[test]
+    fn readmitted_after_cooldown_and_probation() {
+        let policy = HealthPolicy::default();
+        let now = Instant::now();
+        let mut health = ProxyHealth::default();
+
+        health.record(ProxyOutcome::Blocked, &policy, now);
+        assert!(!health.is_available(now));
+        let later = now + Duration::from_secs(31);
+        assert!(health.is_available(later));
+        assert_eq!(health.state, HealthState::Probation { successes: 0 });
+
+        for _ in 0..3 {
+            health.record(ProxyOutcome::Success, &policy, later);
+        }
+        assert_eq!(health.state, HealthState::Healthy);
+    }
+
+    #[test]
+    fn failure_on_probation_quarantines() {
+        let policy = HealthPolicy::default();
+        let now = Instant::now();
+        let mut health = ProxyHealth {
+            state: HealthState::Probation { successes: 1 },
+            ..ProxyHealth::default()
+        };
+        health.record(ProxyOutcome::Blocked, &policy, now);
+        assert!(matches!(health.state, HealthState::Quarantined { .. }));
+    }
+
+    #[test]
+    fn long_penalties_are_capped() {
+        let policy = HealthPolicy {
+            base_cooldown_secs: u64::MAX,
+            max_cooldown_secs: u64::MAX,
+            quarantine_secs: u64::MAX,
+            ..HealthPolicy::default()
+        };
+        let now = Instant::now();
+        let capped = now + Duration::from_secs(MAX_PENALTY_SECS);
+        let mut health = ProxyHealth::default();
+
+        health.record(ProxyOutcome::Blocked, &policy, now);
+        assert_eq!(health.state, HealthState::CoolingDown { until: capped });
+        for _ in 0..4 {
+            health.record(ProxyOutcome::Blocked, &policy, now);
+        }
+        assert_eq!(health.state, HealthState::Quarantined { until: capped });
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+// Upper bounds of the histogram buckets in milliseconds, the last bucket
+// holds everything slower
+const BUCKET_BOUNDS_MS: [u64; 11] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+// Weight of the last sample in the smoothed latency used to rank proxies
+const SMOOTHING: f64 = 0.2;
+
+// Timings of a request made through a proxy, measured by the request handler.
+// Every request opens its own connection, so both include connecting to the
+// proxy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestTimings {
+    // From the send until the response headers were received
+    pub first_byte: Duration,
+    // Until the body was read, None when it was not
+    pub total: Option<Duration>,
+}
+
+// Latency distribution with fixed buckets, cheap enough to update on every request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| ms <= bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0)
+            .then(|| Duration::from_secs_f64(self.sum.as_secs_f64() / self.count as f64))
+    }
+
+    // Upper bound of the bucket holding the given quantile (0.0 to 1.0),
+    // the slowest bucket reports the slowest sample
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &samples) in self.buckets.iter().enumerate() {
+            seen += samples;
+            if seen >= rank {
+                return match BUCKET_BOUNDS_MS.get(bucket) {
+                    Some(&bound) => Some(Duration::from_millis(bound)),
+                    None => Some(self.max),
+                };
+            }
+        }
+        Some(self.max)
+    }
+}
+
+// Latency of a proxy: time to first byte and total time of the requests
+// made through it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxyLatency {
+    pub first_byte: LatencyHistogram,
+    pub total: LatencyHistogram,
+    // Exponentially smoothed time to first byte, follows recent slowdowns
+    smoothed_ms: Option<f64>,
+}
+
+impl ProxyLatency {
+    pub fn record(&mut self, timings: &RequestTimings) {
+        self.first_byte.record(timings.first_byte);
+        if let Some(total) = timings.total {
+            self.total.record(total);
+        }
+        let ms = timings.first_byte.as_secs_f64() * 1000.0;
+        self.smoothed_ms = Some(match self.smoothed_ms {
+            Some(smoothed) => smoothed + SMOOTHING * (ms - smoothed),
+            None => ms,
+        });
+    }
+
+    // Recent time to first byte in milliseconds, None until a request succeeded
+    pub fn score(&self) -> Option<f64> {
+        self.smoothed_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_come_from_the_buckets() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+        for ms in [5, 20, 40, 80, 90, 200, 400, 900, 2000, 60000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 10);
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.percentile(0.9), Some(Duration::from_millis(2500)));
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn score_follows_recent_requests() {
+        let mut latency = ProxyLatency::default();
+        assert_eq!(latency.score(), None);
+        let timings = |ms| RequestTimings {
+            first_byte: Duration::from_millis(ms),
+            total: None,
+        };
+        latency.record(&timings(100));
+        latency.record(&timings(600));
+        assert_eq!(latency.score(), Some(200.0));
+        assert_eq!(latency.total.count(), 0);
+    }
+}
@@ -0,0 +1,1100 @@
+use log::warn;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::lenient;
+use crate::proxy_checker::ProbeResult;
+use crate::proxy_handler::{ProxyHandler, ProxyTargeting};
+use crate::proxy_health::{penalty_end, HealthPolicy, ProxyHealth, ProxyOutcome};
+use crate::proxy_latency::{ProxyLatency, RequestTimings};
+use crate::proxy_list::{redact_proxy_url, url_subnet, ProxySpec};
+use crate::proxy_provider::{ProxyProvider, StaticProvider};
+use crate::proxy_state::{
+    instant_to_unix, unix_secs, unix_to_instant, HealthSnapshot, PoolSnapshot, ProxySnapshot,
+    SubnetBanSnapshot,
+};
+use crate::rate_limit::{RateLimit, TokenBucket};
+
+// How the next proxy is picked from the pool, configurable per site
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+    Weighted,
+    LeastRecentlyUsed,
+    LeastInFlight,
+    // Lowest recent time to first byte, with some exploration of the others
+    Fastest,
+}
+
+// Share of the picks of the fastest strategy going to a random proxy so the
+// latency of the others stays known
+const FASTEST_EXPLORATION: f64 = 0.1;
+
+// A proxy of the pool with its relative weight for the weighted strategy,
+// the tags of its line in the proxies file and the subnet it exits from
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyEntry {
+    pub url: String,
+    pub weight: u32,
+    pub tags: BTreeMap<String, String>,
+    pub subnet: Option<String>,
+    // Line of the proxies file the URL was built from, None for proxies
+    // given by their URL. The provider builds targeted URLs from it.
+    pub spec: Option<ProxySpec>,
+}
+
+impl ProxyEntry {
+    pub fn new(url: String) -> Self {
+        ProxyEntry {
+            subnet: url_subnet(&url),
+            url,
+            weight: 1,
+            tags: BTreeMap::new(),
+            spec: None,
+        }
+    }
+}
+
+// How the pool spreads its picks across subnets and bans whole subnets
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct SubnetPolicy {
+    // Consecutive picks of a site go to different subnets when possible
+    #[serde(deserialize_with = "lenient")]
+    pub spread: bool,
+    // Distinct proxies of a subnet blocked by a site within window_secs
+    // before the whole subnet is banned for that site, 0 never bans
+    #[serde(deserialize_with = "lenient")]
+    pub ban_after: u32,
+    #[serde(deserialize_with = "lenient")]
+    pub window_secs: u64,
+    #[serde(deserialize_with = "lenient")]
+    pub ban_secs: u64,
+}
+
+impl Default for SubnetPolicy {
+    fn default() -> Self {
+        SubnetPolicy {
+            spread: true,
+            ban_after: 3,
+            window_secs: 600,
+            ban_secs: 3600,
+        }
+    }
+}
+
+// Recent blocks and bans of a subnet, per site
+#[derive(Default)]
+struct SubnetState {
+    blocks: HashMap<String, Vec<(Instant, String)>>,
+    banned_until: HashMap<String, Instant>,
+}
+
+impl SubnetState {
+    // Record a proxy blocked by the site, true when the subnet gets banned
+    fn record_block(
+        &mut self,
+        site: &str,
+        proxy: &str,
+        policy: &SubnetPolicy,
+        now: Instant,
+    ) -> bool {
+        if policy.ban_after == 0 {
+            return false;
+        }
+        let window = Duration::from_secs(policy.window_secs);
+        let blocks = self.blocks.entry(site.to_string()).or_default();
+        blocks.retain(|(at, _)| now.saturating_duration_since(*at) < window);
+        blocks.push((now, proxy.to_string()));
+        let proxies: HashSet<&str> = blocks.iter().map(|(_, proxy)| proxy.as_str()).collect();
+        if proxies.len() < policy.ban_after as usize {
+            return false;
+        }
+        blocks.clear();
+        self.banned_until
+            .insert(site.to_string(), penalty_end(now, policy.ban_secs));
+        true
+    }
+
+    fn is_banned(&self, site: &str, now: Instant) -> bool {
+        self.banned_until
+            .get(site)
+            .is_some_and(|&until| now < until)
+    }
+}
+
+// Selection state of a site: where round robin continues and the subnet
+// of the last proxy handed out
+#[derive(Debug, Default)]
+struct Cursor {
+    next: usize,
+    last_subnet: Option<String>,
+}
+
+struct PoolEntry {
+    entry: ProxyEntry,
+    last_used: Option<Instant>,
+    in_flight: usize,
+    // Health per site, a proxy blocked by one site stays usable for the others
+    health: HashMap<String, ProxyHealth>,
+    // Sites that removed the proxy for good, with the time of the ban
+    banned: HashMap<String, SystemTime>,
+    // Last background probe, the proxy is skipped until it is reachable again
+    probe: Option<ProbeResult>,
+    // No longer handed out, removed once its last request is done
+    draining: bool,
+    latency: ProxyLatency,
+    // Request budget per site, for the sites with a rate limit
+    budgets: HashMap<String, TokenBucket>,
+}
+
+impl PoolEntry {
+    fn new(entry: ProxyEntry) -> Self {
+        PoolEntry {
+            entry,
+            last_used: None,
+            in_flight: 0,
+            health: HashMap::new(),
+            banned: HashMap::new(),
+            probe: None,
+            draining: false,
+            latency: ProxyLatency::default(),
+            budgets: HashMap::new(),
+        }
+    }
+
+    // The proxy given by its URL, with or without its password
+    fn matches(&self, proxy: &str) -> bool {
+        self.entry.url == proxy || redact_proxy_url(&self.entry.url) == proxy
+    }
+
+    fn is_available(
+        &mut self,
+        site: &str,
+        rate_limit: Option<&RateLimit>,
+        subnets: &HashMap<String, SubnetState>,
+        now: Instant,
+    ) -> bool {
+        !self.draining
+            && self.probe.is_none_or(|probe| probe.reachable)
+            && !self.banned.contains_key(site)
+            && self
+                .health
+                .get_mut(site)
+                .is_none_or(|health| health.is_available(now))
+            && rate_limit.is_none_or(|limit| self.budget(site, limit, now).has_token(limit, now))
+            && self.entry.subnet.as_ref().is_none_or(|subnet| {
+                subnets
+                    .get(subnet)
+                    .is_none_or(|state| !state.is_banned(site, now))
+            })
+    }
+
+    fn budget(&mut self, site: &str, limit: &RateLimit, now: Instant) -> &mut TokenBucket {
+        self.budgets
+            .entry(site.to_string())
+            .or_insert_with(|| TokenBucket::new(limit, now))
+    }
+
+    // Hand out the proxy for one request of the site
+    fn lease(&mut self, site: &str, rate_limit: Option<&RateLimit>, now: Instant) -> String {
+        if let Some(limit) = rate_limit {
+            self.budget(site, limit, now).try_take(limit, now);
+        }
+        self.last_used = Some(now);
+        self.in_flight += 1;
+        self.entry.url.clone()
+    }
+}
+
+struct PoolState {
+    entries: Vec<PoolEntry>,
+    // Exits picked by the provider for targeted requests, one per targeted
+    // URL. Their outcomes are tracked here and never charged to a listed proxy.
+    exits: Vec<PoolEntry>,
+    health_policy: HealthPolicy,
+    provider: Arc<dyn ProxyProvider>,
+    subnet_policy: SubnetPolicy,
+    subnets: HashMap<String, SubnetState>,
+}
+
+// Snapshot of a proxy of the pool, its URL has no password
+#[derive(Debug, Clone)]
+pub struct ProxyStatus {
+    pub proxy: String,
+    pub weight: u32,
+    pub tags: BTreeMap<String, String>,
+    pub subnet: Option<String>,
+    pub in_flight: usize,
+    pub last_used: Option<Instant>,
+    pub probe: Option<ProbeResult>,
+    pub draining: bool,
+    pub latency: ProxyLatency,
+    pub health: BTreeMap<String, ProxyHealth>,
+    pub banned: Vec<String>,
+}
+
+// Proxies of a pool shared by every site using it. Usage is tracked per proxy
+// while health and bans are tracked per (proxy, site); sites get their
+// proxies through a SiteProxies view with their name and strategy.
+pub struct ProxyPool {
+    state: Mutex<PoolState>,
+}
+
+impl ProxyPool {
+    pub fn new(entries: Vec<ProxyEntry>) -> Self {
+        ProxyPool {
+            state: Mutex::new(PoolState {
+                entries: entries.into_iter().map(PoolEntry::new).collect(),
+                exits: Vec::new(),
+                health_policy: HealthPolicy::default(),
+                provider: Arc::new(StaticProvider::new(None, None)),
+                subnet_policy: SubnetPolicy::default(),
+                subnets: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn with_health_policy(self, health_policy: HealthPolicy) -> Self {
+        self.set_health_policy(health_policy);
+        self
+    }
+
+    pub fn set_health_policy(&self, health_policy: HealthPolicy) {
+        self.state.lock().unwrap().health_policy = health_policy;
+    }
+
+    pub fn with_subnet_policy(self, subnet_policy: SubnetPolicy) -> Self {
+        self.set_subnet_policy(subnet_policy);
+        self
+    }
+
+    pub fn set_subnet_policy(&self, subnet_policy: SubnetPolicy) {
+        self.state.lock().unwrap().subnet_policy = subnet_policy;
+    }
+
+    // Provider turning the proxies added at runtime into URLs
+    pub fn with_provider(self, provider: Arc<dyn ProxyProvider>) -> Self {
+        self.set_provider(provider);
+        self
+    }
+
+    pub fn set_provider(&self, provider: Arc<dyn ProxyProvider>) {
+        self.state.lock().unwrap().provider = provider;
+    }
+
+    // Replace the proxies of the pool, the ones kept keep their usage and health
+    pub fn set_entries(&self, entries: Vec<ProxyEntry>) {
+        let mut state = self.state.lock().unwrap();
+        let mut previous: HashMap<String, PoolEntry> = state
+            .entries
+            .drain(..)
+            .map(|pool_entry| (pool_entry.entry.url.clone(), pool_entry))
+            .collect();
+        state.entries = entries
+            .into_iter()
+            .map(|entry| match previous.remove(&entry.url) {
+                Some(pool_entry) => PoolEntry {
+                    entry,
+                    ..pool_entry
+                },
+                None => PoolEntry::new(entry),
+            })
+            .collect();
+    }
+
+    // View of the pool handing out proxies for one site
+    pub fn site(self: &Arc<Self>, site: &str, strategy: SelectionStrategy) -> SiteProxies {
+        SiteProxies {
+            pool: self.clone(),
+            site: site.to_string(),
+            strategy,
+            rate_limit: None,
+            cursor: Cursor::default(),
+        }
+    }
+
+    // Health of every proxy of the pool for the given site
+    pub fn health(&self, site: &str) -> Vec<(String, ProxyHealth)> {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|pool_entry| {
+                let health = pool_entry.health.get(site).cloned().unwrap_or_default();
+                (pool_entry.entry.url.clone(), health)
+            })
+            .collect()
+    }
+
+    // URL of every proxy of the pool
+    pub fn urls(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|pool_entry| pool_entry.entry.url.clone())
+            .collect()
+    }
+
+    // Last background probe of every proxy of the pool
+    pub fn probes(&self) -> Vec<(String, Option<ProbeResult>)> {
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|pool_entry| (pool_entry.entry.url.clone(), pool_entry.probe))
+            .collect()
+    }
+
+    pub fn record_probe(&self, proxy: &str, result: ProbeResult) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pool_entry) = state
+            .entries
+            .iter_mut()
+            .find(|pool_entry| pool_entry.entry.url == proxy)
+        {
+            pool_entry.probe = Some(result);
+        }
+    }
+
+    // Timings of a request made through the proxy
+    pub fn record_latency(&self, proxy: &str, timings: &RequestTimings) {
+        self.update_entry(proxy, |pool_entry| pool_entry.latency.record(timings));
+    }
+
+    // Subnets banned for a site with the time left, as (subnet, site, remaining)
+    pub fn subnet_bans(&self) -> Vec<(String, String, Duration)> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        let mut bans: Vec<(String, String, Duration)> = state
+            .subnets
+            .iter()
+            .flat_map(|(subnet, subnet_state)| {
+                subnet_state
+                    .banned_until
+                    .iter()
+                    .filter(|(_, &until)| now < until)
+                    .map(move |(site, &until)| {
+                        (subnet.clone(), site.clone(), until.duration_since(now))
+                    })
+            })
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    // Health, bans and subnet bans worth saving, proxies are identified
+    // by their URL without password
+    pub fn snapshot(&self) -> PoolSnapshot {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let state = self.state.lock().unwrap();
+        let proxies = state
+            .entries
+            .iter()
+            .filter(|pool_entry| !pool_entry.health.is_empty() || !pool_entry.banned.is_empty())
+            .map(|pool_entry| ProxySnapshot {
+                proxy: redact_proxy_url(&pool_entry.entry.url),
+                health: pool_entry
+                    .health
+                    .iter()
+                    .map(|(site, health)| (site.clone(), HealthSnapshot::new(health, now, wall)))
+                    .collect(),
+                bans: pool_entry
+                    .banned
+                    .iter()
+                    .map(|(site, &banned_at)| (site.clone(), unix_secs(banned_at)))
+                    .collect(),
+            })
+            .collect();
+        let subnet_bans = state
+            .subnets
+            .iter()
+            .flat_map(|(subnet, subnet_state)| {
+                subnet_state
+                    .banned_until
+                    .iter()
+                    .filter(|(_, &until)| now < until)
+                    .map(move |(site, &until)| SubnetBanSnapshot {
+                        subnet: subnet.clone(),
+                        site: site.clone(),
+                        until: instant_to_unix(until, now, wall),
+                    })
+            })
+            .collect();
+        PoolSnapshot {
+            proxies,
+            subnet_bans,
+        }
+    }
+
+    // Apply a snapshot saved by a previous process, proxies no longer in the
+    // pool are skipped. Returns the number of proxies restored.
+    pub fn restore(&self, snapshot: &PoolSnapshot) -> usize {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let mut state = self.state.lock().unwrap();
+        let mut restored = 0;
+        for proxy in &snapshot.proxies {
+            let Some(pool_entry) = state.entries.iter_mut().find(|e| e.matches(&proxy.proxy))
+            else {
+                continue;
+            };
+            for (site, health) in &proxy.health {
+                pool_entry
+                    .health
+                    .insert(site.clone(), health.health(now, wall));
+            }
+            for (site, &banned_at) in &proxy.bans {
+                pool_entry
+                    .banned
+                    .insert(site.clone(), UNIX_EPOCH + Duration::from_secs(banned_at));
+            }
+            restored += 1;
+        }
+        for ban in &snapshot.subnet_bans {
+            state
+                .subnets
+                .entry(ban.subnet.clone())
+                .or_default()
+                .banned_until
+                .insert(ban.site.clone(), unix_to_instant(ban.until, now, wall));
+        }
+        restored
+    }
+
+    // Status of the proxies of the pool followed by the targeted exits
+    pub fn status(&self) -> Vec<ProxyStatus> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter()
+            .chain(&state.exits)
+            .map(|pool_entry| ProxyStatus {
+                proxy: redact_proxy_url(&pool_entry.entry.url),
+                weight: pool_entry.entry.weight,
+                tags: pool_entry.entry.tags.clone(),
+                subnet: pool_entry.entry.subnet.clone(),
+                in_flight: pool_entry.in_flight,
+                last_used: pool_entry.last_used,
+                probe: pool_entry.probe,
+                draining: pool_entry.draining,
+                latency: pool_entry.latency.clone(),
+                health: pool_entry
+                    .health
+                    .iter()
+                    .map(|(site, health)| (site.clone(), health.clone()))
+                    .collect(),
+                banned: pool_entry.banned.keys().cloned().collect(),
+            })
+            .collect()
+    }
+
+    // Add a proxy, resolved by the provider of the pool. Returns the proxy
+    // without its password, or None when it is already in the pool.
+    pub fn add(&self, proxy: &ProxySpec, weight: u32) -> Result<Option<String>, String> {
+        let mut state = self.state.lock().unwrap();
+        let url = state.provider.proxy_url(proxy)?;
+        if state
+            .entries
+            .iter()
+            .any(|pool_entry| pool_entry.entry.url == url)
+        {
+            return Ok(None);
+        }
+        state.entries.push(PoolEntry::new(ProxyEntry {
+            url: url.clone(),
+            weight,
+            tags: proxy.tags.clone(),
+            subnet: proxy.subnet(),
+            spec: Some(proxy.clone()),
+        }));
+        Ok(Some(redact_proxy_url(&url)))
+    }
+
+    // Stop handing out a proxy and remove it once its requests are done,
+    // false when the proxy is not in the pool
+    pub fn drain(&self, proxy: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.entries.iter().position(|e| e.matches(proxy)) else {
+            return false;
+        };
+        if state.entries[index].in_flight == 0 {
+            state.entries.remove(index);
+        } else {
+            state.entries[index].draining = true;
+        }
+        true
+    }
+
+    // Stop using a proxy for one site, false when it is not in the pool
+    pub fn ban(&self, site: &str, proxy: &str) -> bool {
+        self.update_entry(proxy, |pool_entry| {
+            pool_entry
+                .banned
+                .insert(site.to_string(), SystemTime::now());
+        })
+    }
+
+    // Lift the ban of a proxy for one site and forget its failures there
+    pub fn unban(&self, site: &str, proxy: &str) -> bool {
+        self.update_entry(proxy, |pool_entry| {
+            pool_entry.banned.remove(site);
+            pool_entry.health.remove(site);
+        })
+    }
+
+    // Lift the ban of a subnet for one site and forget its recent blocks
+    // there, false when the subnet is not banned for the site
+    pub fn unban_subnet(&self, site: &str, subnet: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(subnet_state) = state.subnets.get_mut(subnet) else {
+            return false;
+        };
+        subnet_state.blocks.remove(site);
+        subnet_state.banned_until.remove(site).is_some()
+    }
+
+    fn update_entry(&self, proxy: &str, update: impl FnOnce(&mut PoolEntry)) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let PoolState { entries, exits, .. } = &mut *state;
+        match entries.iter_mut().chain(exits).find(|e| e.matches(proxy)) {
+            Some(pool_entry) => {
+                update(pool_entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn acquire(
+        &self,
+        site: &str,
+        strategy: SelectionStrategy,
+        rate_limit: Option<&RateLimit>,
+        targeting: &ProxyTargeting,
+        cursor: &mut Cursor,
+    ) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let PoolState {
+            entries,
+            exits,
+            provider,
+            subnet_policy,
+            subnets,
+            ..
+        } = &mut *state;
+        let now = Instant::now();
+        let mut indexes: Vec<usize> = (0..entries.len())
+            .filter(|&i| entries[i].is_available(site, rate_limit, subnets, now))
+            .collect();
+        if indexes.is_empty() {
+            if !entries.is_empty() {
+                warn!(
+                    "Every proxy of the pool is cooling down, quarantined, out of budget or in a banned subnet for {}",
+                    site
+                );
+            }
+            return None;
+        }
+        // Skip the subnet of the previous pick unless it is the only one left
+        if let (true, Some(last)) = (subnet_policy.spread, &cursor.last_subnet) {
+            let others: Vec<usize> = indexes
+                .iter()
+                .copied()
+                .filter(|&i| entries[i].entry.subnet.as_ref() != Some(last))
+                .collect();
+            if !others.is_empty() {
+                indexes = others;
+            }
+        }
+
+        let index = select(entries, indexes, strategy, &mut cursor.next)?;
+        // The provider picks the exit of a targeted request, so the request is
+        // tracked under the targeted URL instead of the listed proxy
+        let targeted_url = match &entries[index].entry.spec {
+            Some(spec) if !targeting.is_empty() => provider.targeted_url(spec, targeting),
+            _ => None,
+        };
+        if let Some(url) = targeted_url {
+            let exit = match exits.iter().position(|exit| exit.entry.url == url) {
+                Some(position) => &mut exits[position],
+                None => {
+                    exits.push(PoolEntry::new(ProxyEntry::new(url)));
+                    exits.last_mut().unwrap()
+                }
+            };
+            if !exit.is_available(site, rate_limit, subnets, now) {
+                warn!(
+                    "The targeted exit {} is cooling down, quarantined or out of budget for {}",
+                    redact_proxy_url(&exit.entry.url),
+                    site
+                );
+                return None;
+            }
+            return Some(exit.lease(site, rate_limit, now));
+        }
+        let pool_entry = &mut entries[index];
+        cursor.last_subnet = pool_entry.entry.subnet.clone();
+        Some(pool_entry.lease(site, rate_limit, now))
+    }
+
+    // Failures put the proxy in cooldown for the site instead of removing it
+    fn release(&self, site: &str, proxy: &str, outcome: ProxyOutcome) {
+        let mut state = self.state.lock().unwrap();
+        let PoolState {
+            entries,
+            exits,
+            health_policy,
+            subnet_policy,
+            subnets,
+            ..
+        } = &mut *state;
+        if let Some(exit) = exits.iter_mut().find(|exit| exit.entry.url == proxy) {
+            exit.in_flight = exit.in_flight.saturating_sub(1);
+            exit.health.entry(site.to_string()).or_default().record(
+                outcome,
+                health_policy,
+                Instant::now(),
+            );
+        } else if let Some(index) = entries
+            .iter()
+            .position(|pool_entry| pool_entry.entry.url == proxy)
+        {
+            let now = Instant::now();
+            let pool_entry = &mut entries[index];
+            pool_entry.in_flight = pool_entry.in_flight.saturating_sub(1);
+            pool_entry
+                .health
+                .entry(site.to_string())
+                .or_default()
+                .record(outcome, health_policy, now);
+            // Blocks of several proxies of a subnet usually mean the whole subnet is
+            if let (ProxyOutcome::Blocked, Some(subnet)) = (outcome, &pool_entry.entry.subnet) {
+                let state = subnets.entry(subnet.clone()).or_default();
+                if state.record_block(site, proxy, subnet_policy, now) {
+                    warn!(
+                        "Banning subnet {} for {} for {}s, {} of its proxies were blocked",
+                        subnet, site, subnet_policy.ban_secs, subnet_policy.ban_after
+                    );
+                }
+            }
+            if pool_entry.draining && pool_entry.in_flight == 0 {
+                entries.remove(index);
+            }
+        }
+    }
+}
+
+// Pick one of the available proxies given by their index, in ascending order
+fn select(
+    entries: &[PoolEntry],
+    indexes: Vec<usize>,
+    strategy: SelectionStrategy,
+    next: &mut usize,
+) -> Option<usize> {
+    let mut rng = rand::thread_rng();
+    match strategy {
+        SelectionStrategy::Random => indexes.choose(&mut rng).copied(),
+        // Next available proxy after the last one handed out
+        SelectionStrategy::RoundRobin => {
+            let start = *next % entries.len();
+            let index = indexes
+                .iter()
+                .copied()
+                .find(|&i| i >= start)
+                .unwrap_or(indexes[0]);
+            *next = index + 1;
+            Some(index)
+        }
+        SelectionStrategy::Weighted => indexes
+            .choose_weighted(&mut rng, |&i| entries[i].entry.weight)
+            .ok()
+            .copied()
+            // Every weight is 0, fall back to a uniform choice
+            .or_else(|| indexes.choose(&mut rng).copied()),
+        // Never used proxies first, then the one idle for the longest time
+        SelectionStrategy::LeastRecentlyUsed => {
+            indexes.into_iter().min_by_key(|&i| entries[i].last_used)
+        }
+        // Ties go to the least recently used proxy so the load keeps rotating
+        SelectionStrategy::LeastInFlight => indexes
+            .into_iter()
+            .min_by_key(|&i| (entries[i].in_flight, entries[i].last_used)),
+        // Proxies never measured are tried first. The recent latency is scaled by
+        // the requests in flight so a fast proxy does not take the whole load.
+        SelectionStrategy::Fastest => {
+            let (measured, unmeasured): (Vec<usize>, Vec<usize>) = indexes
+                .into_iter()
+                .partition(|&i| entries[i].latency.score().is_some());
+            if !unmeasured.is_empty() {
+                return unmeasured
+                    .into_iter()
+                    .min_by_key(|&i| (entries[i].in_flight, entries[i].last_used));
+            }
+            if rng.gen_bool(FASTEST_EXPLORATION) {
+                return measured.choose(&mut rng).copied();
+            }
+            measured.into_iter().min_by(|&a, &b| {
+                let load = |i: usize| {
+                    entries[i].latency.score().unwrap_or_default()
+                        * (1 + entries[i].in_flight) as f64
+                };
+                load(a).total_cmp(&load(b))
+            })
+        }
+    }
+}
+
+// Proxy handler of one site: proxies come from the shared pool, bans and
+// health only apply to that site
+pub struct SiteProxies {
+    pool: Arc<ProxyPool>,
+    site: String,
+    strategy: SelectionStrategy,
+    rate_limit: Option<RateLimit>,
+    cursor: Cursor,
+}
+
+impl SiteProxies {
+    // Limit the requests each proxy sends to the site, proxies out of
+    // budget are skipped until their bucket refills
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn pool(&self) -> &Arc<ProxyPool> {
+        &self.pool
+    }
+
+    pub fn site(&self) -> &str {
+        &self.site
+    }
+}
+
+impl ProxyHandler for SiteProxies {
+    fn get_proxy(&mut self) -> Option<String> {
+        self.get_targeted_proxy(&ProxyTargeting::default())
+    }
+
+    fn remove(&mut self, proxy: &str) {
+        self.pool.ban(&self.site, proxy);
+    }
+
+    fn release(&mut self, proxy: &str, outcome: ProxyOutcome) {
+        self.pool.release(&self.site, proxy, outcome);
+    }
+
+    fn record_latency(&mut self, proxy: &str, timings: &RequestTimings) {
+        self.pool.record_latency(proxy, timings);
+    }
+
+    fn get_targeted_proxy(&mut self, targeting: &ProxyTargeting) -> Option<String> {
+        self.pool.acquire(
+            &self.site,
+            self.strategy,
+            self.rate_limit.as_ref(),
+            targeting,
+            &mut self.cursor,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared_pool() -> Arc<ProxyPool> {
+        let entries = ["http://a:1", "http://b:1", "http://c:1"]
+            .iter()
+            .map(|url| ProxyEntry::new(url.to_string()))
+            .collect();
+        Arc::new(ProxyPool::new(entries))
+    }
+
+    fn pool(strategy: SelectionStrategy) -> SiteProxies {
+        shared_pool().site("example.com", strategy)
+    }
+
+    #[test]
+    fn round_robin_cycles_through_the_pool() {
+        let mut pool = pool(SelectionStrategy::RoundRobin);
+        let picked: Vec<String> = (0..4).filter_map(|_| pool.get_proxy()).collect();
+        assert_eq!(
+            picked,
+            ["http://a:1", "http://b:1", "http://c:1", "http://a:1"]
+        );
+    }
+
+    #[test]
+    fn least_in_flight_skips_busy_proxies() {
+        let mut pool = pool(SelectionStrategy::LeastInFlight);
+        let first = pool.get_proxy().unwrap();
+        let second = pool.get_proxy().unwrap();
+        assert_ne!(first, second);
+        pool.release(&first, ProxyOutcome::Success);
+        let third = pool.get_proxy().unwrap();
+        assert_ne!(third, second);
+    }
+
+    #[test]
+    fn blocked_proxies_cool_down_instead_of_leaving_the_pool() {
+        let mut pool = pool(SelectionStrategy::RoundRobin);
+        let blocked = pool.get_proxy().unwrap();
+        pool.release(&blocked, ProxyOutcome::Blocked);
+        for _ in 0..4 {
+            assert_ne!(pool.get_proxy().unwrap(), blocked);
+        }
+        assert_eq!(pool.pool().len(), 3);
+    }
+
+    #[test]
+    fn bans_only_apply_to_the_site() {
+        let shared = shared_pool();
+        let mut realestate = shared.site("realestate", SelectionStrategy::RoundRobin);
+        let mut property = shared.site("property", SelectionStrategy::RoundRobin);
+
+        let banned = realestate.get_proxy().unwrap();
+        realestate.release(&banned, ProxyOutcome::Blocked);
+        realestate.remove(&banned);
+        for _ in 0..4 {
+            assert_ne!(realestate.get_proxy().unwrap(), banned);
+        }
+        assert_eq!(property.get_proxy().unwrap(), banned);
+        assert_eq!(shared.health("property")[0].1.failures, 0);
+        assert_eq!(shared.health("realestate")[0].1.failures, 1);
+    }
+
+    #[test]
+    fn set_entries_keeps_the_health_of_kept_proxies() {
+        let shared = shared_pool();
+        let mut site = shared.site("example.com", SelectionStrategy::RoundRobin);
+        let blocked = site.get_proxy().unwrap();
+        site.release(&blocked, ProxyOutcome::Blocked);
+
+        shared.set_entries(vec![
+            ProxyEntry::new(blocked.clone()),
+            ProxyEntry::new("http://d:1".to_string()),
+        ]);
+        assert_eq!(shared.len(), 2);
+        assert_eq!(shared.health("example.com")[0].1.failures, 1);
+        assert_eq!(site.get_proxy().unwrap(), "http://d:1");
+    }
+
+    #[test]
+    fn proxies_failing_their_probe_are_skipped() {
+        let shared = shared_pool();
+        let mut site = shared.site("example.com", SelectionStrategy::RoundRobin);
+        let probe = |reachable| ProbeResult {
+            reachable,
+            latency: std::time::Duration::from_millis(100),
+            checked_at: Instant::now(),
+        };
+        shared.record_probe("http://a:1", probe(false));
+        for _ in 0..4 {
+            assert_ne!(site.get_proxy().unwrap(), "http://a:1");
+        }
+        shared.record_probe("http://a:1", probe(true));
+        assert!((0..3).any(|_| site.get_proxy().unwrap() == "http://a:1"));
+    }
+
+    #[test]
+    fn drained_proxies_leave_once_their_requests_are_done() {
+        let shared = shared_pool();
+        let mut site = shared.site("example.com", SelectionStrategy::RoundRobin);
+        let busy = site.get_proxy().unwrap();
+        assert!(shared.drain(&busy));
+        assert_eq!(shared.len(), 3);
+        for _ in 0..4 {
+            assert_ne!(site.get_proxy().unwrap(), busy);
+        }
+        site.release(&busy, ProxyOutcome::Success);
+        assert_eq!(shared.len(), 2);
+        assert!(!shared.drain(&busy));
+    }
+
+    #[test]
+    fn unban_restores_the_proxy_for_the_site() {
+        let shared = shared_pool();
+        assert!(shared.ban("example.com", "http://a:1"));
+        assert_eq!(shared.status()[0].banned, ["example.com"]);
+        assert!(shared.unban("example.com", "http://a:1"));
+        assert!(shared.status()[0].banned.is_empty());
+        assert!(!shared.ban("example.com", "http://missing:1"));
+    }
+
+    #[test]
+    fn weighted_never_picks_zero_weight_proxies() {
+        let entries = vec![
+            ProxyEntry {
+                weight: 0,
+                ..ProxyEntry::new("http://a:1".to_string())
+            },
+            ProxyEntry {
+                weight: 5,
+                ..ProxyEntry::new("http://b:1".to_string())
+            },
+        ];
+        let mut pool =
+            Arc::new(ProxyPool::new(entries)).site("example.com", SelectionStrategy::Weighted);
+        for _ in 0..20 {
+            assert_eq!(pool.get_proxy().unwrap(), "http://b:1");
+        }
+    }
+
+    #[test]
+    fn fastest_prefers_low_latency_proxies() {
+        let mut pool = pool(SelectionStrategy::Fastest);
+        // Every proxy is measured once before the latency decides
+        for ms in [300, 20, 900] {
+            let proxy = pool.get_proxy().unwrap();
+            let timings = RequestTimings {
+                first_byte: Duration::from_millis(ms),
+                total: None,
+            };
+            pool.record_latency(&proxy, &timings);
+            pool.release(&proxy, ProxyOutcome::Success);
+        }
+        let fastest = "http://b:1";
+        let picks = (0..100)
+            .filter(|_| {
+                let proxy = pool.get_proxy().unwrap();
+                pool.release(&proxy, ProxyOutcome::Success);
+                proxy == fastest
+            })
+            .count();
+        assert!(picks > 70, "fastest proxy picked {} times", picks);
+        let status = pool.pool().status();
+        assert_eq!(status[1].latency.first_byte.count(), 1);
+    }
+
+    #[test]
+    fn proxies_out_of_budget_are_skipped() {
+        let limit = RateLimit {
+            requests_per_sec: 0.01,
+            burst: 1,
+        };
+        let shared = shared_pool();
+        let mut realestate = shared
+            .site("realestate", SelectionStrategy::RoundRobin)
+            .with_rate_limit(Some(limit));
+        let mut used = Vec::new();
+        for _ in 0..3 {
+            let proxy = realestate.get_proxy().unwrap();
+            realestate.release(&proxy, ProxyOutcome::Success);
+            used.push(proxy);
+        }
+        used.sort();
+        assert_eq!(used, ["http://a:1", "http://b:1", "http://c:1"]);
+        assert_eq!(realestate.get_proxy(), None);
+
+        // Other sites have their own budget
+        let mut property = shared.site("property", SelectionStrategy::RoundRobin);
+        assert!(property.get_proxy().is_some());
+    }
+
+    fn subnet_pool() -> Arc<ProxyPool> {
+        let entries = [
+            "http://10.0.1.1:1",
+            "http://10.0.1.2:1",
+            "http://10.0.1.3:1",
+            "http://10.0.2.1:1",
+        ]
+        .iter()
+        .map(|url| ProxyEntry::new(url.to_string()))
+        .collect();
+        Arc::new(ProxyPool::new(entries))
+    }
+
+    #[test]
+    fn consecutive_picks_change_subnet() {
+        let mut pool = subnet_pool().site("example.com", SelectionStrategy::RoundRobin);
+        let subnets: Vec<bool> = (0..4)
+            .map(|_| pool.get_proxy().unwrap().starts_with("http://10.0.2."))
+            .collect();
+        assert_eq!(subnets, [false, true, false, true]);
+    }
+
+    #[test]
+    fn blocks_across_a_subnet_ban_it() {
+        let shared = subnet_pool();
+        let mut pool = shared.site("example.com", SelectionStrategy::RoundRobin);
+        for proxy in [
+            "http://10.0.1.1:1",
+            "http://10.0.1.2:1",
+            "http://10.0.1.3:1",
+        ] {
+            pool.release(proxy, ProxyOutcome::Blocked);
+        }
+        let bans = shared.subnet_bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0, "10.0.1.0/24");
+
+        // Proxies of the subnet cooling down or not, only the other subnet is left
+        shared.unban("example.com", "http://10.0.1.1:1");
+        for _ in 0..3 {
+            assert_eq!(pool.get_proxy().unwrap(), "http://10.0.2.1:1");
+        }
+        // Other sites keep using the subnet
+        let mut other = shared.site("other.com", SelectionStrategy::RoundRobin);
+        assert_eq!(other.get_proxy().unwrap(), "http://10.0.1.1:1");
+    }
+
+    #[test]
+    fn long_subnet_bans_are_capped() {
+        let shared = subnet_pool();
+        shared.set_subnet_policy(SubnetPolicy {
+            ban_secs: u64::MAX,
+            ..SubnetPolicy::default()
+        });
+        let mut pool = shared.site("example.com", SelectionStrategy::RoundRobin);
+        for proxy in [
+            "http://10.0.1.1:1",
+            "http://10.0.1.2:1",
+            "http://10.0.1.3:1",
+        ] {
+            pool.release(proxy, ProxyOutcome::Blocked);
+        }
+        let bans = shared.subnet_bans();
+        assert!(bans[0].2 <= Duration::from_secs(crate::proxy_health::MAX_PENALTY_SECS));
+        assert_eq!(shared.len(), 4);
+    }
+
+    #[test]
+    fn snapshot_restores_bans_and_cooldowns() {
+        let shared = shared_pool();
+        let mut pool = shared.site("example.com", SelectionStrategy::RoundRobin);
+        pool.release("http://a:1", ProxyOutcome::Failed);
+        pool.remove("http://b:1");
+        let snapshot = shared.snapshot();
+        assert_eq!(snapshot.proxies.len(), 2);
+
+        let restarted = shared_pool();
+        assert_eq!(restarted.restore(&snapshot), 2);
+        let mut pool = restarted.site("example.com", SelectionStrategy::RoundRobin);
+        for _ in 0..3 {
+            assert_eq!(pool.get_proxy().unwrap(), "http://c:1");
+        }
+        assert_eq!(restarted.status()[1].banned, ["example.com"]);
+    }
+}
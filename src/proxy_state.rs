@@ -0,0 +1,292 @@
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::PersistenceConfig;
+use crate::proxy_health::{HealthState, ProxyHealth};
+use crate::site_router::SiteRouter;
+
+// Health, bans and subnet bans of every pool, saved so a restart does not
+// hand out proxies the target sites already refused. Times are Unix seconds
+// and proxies are identified by their URL without password.
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct StateFile {
+    pub saved_at: u64,
+    pub pools: BTreeMap<String, PoolSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct PoolSnapshot {
+    pub proxies: Vec<ProxySnapshot>,
+    #[serde(default)]
+    pub subnet_bans: Vec<SubnetBanSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct ProxySnapshot {
+    pub proxy: String,
+    // Health per site
+    #[serde(default)]
+    pub health: BTreeMap<String, HealthSnapshot>,
+    // Sites that banned the proxy, with the time of the ban
+    #[serde(default)]
+    pub bans: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HealthSnapshot {
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub state: StateSnapshot,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StateSnapshot {
+    Healthy,
+    CoolingDown { until: u64 },
+    Quarantined { until: u64 },
+    Probation { successes: u32 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SubnetBanSnapshot {
+    pub subnet: String,
+    pub site: String,
+    pub until: u64,
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Wall clock time of an instant of this process, `now` and `wall` being the same moment
+pub fn instant_to_unix(instant: Instant, now: Instant, wall: SystemTime) -> u64 {
+    if instant >= now {
+        unix_secs(wall + (instant - now))
+    } else {
+        unix_secs(wall - (now - instant))
+    }
+}
+
+// Instant of a saved wall clock time, times already past map to `now`
+pub fn unix_to_instant(secs: u64, now: Instant, wall: SystemTime) -> Instant {
+    let time = UNIX_EPOCH + Duration::from_secs(secs);
+    now + time.duration_since(wall).unwrap_or_default()
+}
+
+impl HealthSnapshot {
+    pub fn new(health: &ProxyHealth, now: Instant, wall: SystemTime) -> Self {
+        let state = match health.state {
+            HealthState::Healthy => StateSnapshot::Healthy,
+            HealthState::CoolingDown { until } => StateSnapshot::CoolingDown {
+                until: instant_to_unix(until, now, wall),
+            },
+            HealthState::Quarantined { until } => StateSnapshot::Quarantined {
+                until: instant_to_unix(until, now, wall),
+            },
+            HealthState::Probation { successes } => StateSnapshot::Probation { successes },
+        };
+        HealthSnapshot {
+            successes: health.successes,
+            failures: health.failures,
+            consecutive_failures: health.consecutive_failures,
+            state,
+        }
+    }
+
+    // An expired cooldown or quarantine turns into probation on the next pick
+    pub fn health(&self, now: Instant, wall: SystemTime) -> ProxyHealth {
+        let state = match self.state {
+            StateSnapshot::Healthy => HealthState::Healthy,
+            StateSnapshot::CoolingDown { until } => HealthState::CoolingDown {
+                until: unix_to_instant(until, now, wall),
+            },
+            StateSnapshot::Quarantined { until } => HealthState::Quarantined {
+                until: unix_to_instant(until, now, wall),
+            },
+            StateSnapshot::Probation { successes } => HealthState::Probation { successes },
+        };
+        ProxyHealth {
+            successes: self.successes,
+            failures: self.failures,
+            consecutive_failures: self.consecutive_failures,
+            state,
+        }
+    }
+}
+
+impl StateFile {
+    // Drop what is older than max_age: the health of a file saved that long
+    // ago, older bans and subnet bans already over
+    pub fn expire(&mut self, max_age: Duration, wall: SystemTime) {
+        let now = unix_secs(wall);
+        let max_age = max_age.as_secs();
+        let stale = now.saturating_sub(self.saved_at) >= max_age;
+        for pool in self.pools.values_mut() {
+            for proxy in &mut pool.proxies {
+                if stale {
+                    proxy.health.clear();
+                }
+                proxy
+                    .bans
+                    .retain(|_, &mut banned_at| now.saturating_sub(banned_at) < max_age);
+            }
+            pool.proxies
+                .retain(|proxy| !proxy.health.is_empty() || !proxy.bans.is_empty());
+            pool.subnet_bans.retain(|ban| ban.until > now);
+        }
+    }
+}
+
+// The saved state, None when the file does not exist yet
+pub fn load_state(path: &str) -> Result<Option<StateFile>, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Written next to the file then renamed, a crash never leaves half a file
+pub fn save_state(path: &str, state: &StateFile) -> Result<(), Box<dyn Error>> {
+    let tmp = format!("{}.tmp", path);
+    if let Some(dir) = Path::new(path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&tmp, serde_json::to_string_pretty(state)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Snapshot of every pool of the router
+pub fn snapshot(router: &SiteRouter) -> StateFile {
+    StateFile {
+        saved_at: unix_secs(SystemTime::now()),
+        pools: router
+            .pools()
+            .into_iter()
+            .map(|(name, pool)| (name, pool.snapshot()))
+            .collect(),
+    }
+}
+
+// Restore the state saved by the previous process into the pools of the router
+pub fn restore(router: &SiteRouter, config: &PersistenceConfig) {
+    let Some(ref path) = config.file else {
+        return;
+    };
+    let mut state = match load_state(path) {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            info!("No proxy state saved in {} yet", path);
+            return;
+        }
+        Err(e) => {
+            warn!("Ignoring the proxy state saved in {}: {}", path, e);
+            return;
+        }
+    };
+    state.expire(Duration::from_secs(config.max_age_secs), SystemTime::now());
+    for (name, snapshot) in &state.pools {
+        match router.pool(name) {
+            Some(pool) => {
+                let restored = pool.restore(snapshot);
+                info!(
+                    "Restored the state of {} proxies of pool {}",
+                    restored, name
+                );
+            }
+            None => warn!("Ignoring the saved state of unknown proxy pool {}", name),
+        }
+    }
+}
+
+pub fn save(router: &SiteRouter, config: &PersistenceConfig) {
+    if let Some(ref path) = config.file {
+        if let Err(e) = save_state(path, &snapshot(router)) {
+            error!("Failed to save the proxy state to {}: {}", path, e);
+        }
+    }
+}
+
+// Start the background task saving the state of the pools periodically
+pub fn spawn_saver(router: Arc<SiteRouter>, config: PersistenceConfig) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.save_interval_secs));
+        // The first tick completes immediately, right after the restore
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            save(&router, &config);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expire_drops_old_records() {
+        let wall = UNIX_EPOCH + Duration::from_secs(100_000);
+        let mut state = StateFile {
+            saved_at: 99_000,
+            pools: BTreeMap::from([(
+                "default".to_string(),
+                PoolSnapshot {
+                    proxies: vec![ProxySnapshot {
+                        proxy: "http://1.2.3.4:1/".to_string(),
+                        health: BTreeMap::new(),
+                        bans: BTreeMap::from([
+                            ("old.com".to_string(), 10_000),
+                            ("new.com".to_string(), 98_000),
+                        ]),
+                    }],
+                    subnet_bans: vec![SubnetBanSnapshot {
+                        subnet: "1.2.3.0/24".to_string(),
+                        site: "new.com".to_string(),
+                        until: 99_500,
+                    }],
+                },
+            )]),
+        };
+        state.expire(Duration::from_secs(86_400), wall);
+        let pool = &state.pools["default"];
+        assert_eq!(pool.proxies[0].bans.keys().collect::<Vec<_>>(), ["new.com"]);
+        assert!(pool.subnet_bans.is_empty());
+    }
+
+    #[test]
+    fn cooldowns_survive_the_round_trip() {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let health = ProxyHealth {
+            failures: 2,
+            consecutive_failures: 2,
+            state: HealthState::CoolingDown {
+                until: now + Duration::from_secs(600),
+            },
+            ..ProxyHealth::default()
+        };
+        let restored = HealthSnapshot::new(&health, now, wall).health(now, wall);
+        let HealthState::CoolingDown { until } = restored.state else {
+            panic!("expected a cooldown, got {:?}", restored.state);
+        };
+        let remaining = until - now;
+        assert!(remaining > Duration::from_secs(598) && remaining <= Duration::from_secs(601));
+        assert_eq!(restored.consecutive_failures, 2);
+    }
+}
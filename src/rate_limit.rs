@@ -0,0 +1,95 @@
+use serde_derive::Deserialize;
+use std::time::Instant;
+
+use crate::config::lenient;
+
+// Requests a single proxy may send to a site: `requests_per_sec` on average
+// with bursts of up to `burst` requests
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RateLimit {
+    #[serde(deserialize_with = "lenient")]
+    pub requests_per_sec: f64,
+    #[serde(default = "default_burst", deserialize_with = "lenient")]
+    pub burst: u32,
+}
+
+fn default_burst() -> u32 {
+    1
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.requests_per_sec > 0.0 && self.requests_per_sec.is_finite()) {
+            return Err("requests_per_sec must be greater than 0".to_string());
+        }
+        if self.burst == 0 {
+            return Err("burst must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Budget of one (proxy, site) pair, refilled continuously up to the burst
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    // A new bucket starts full
+    pub fn new(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_sec).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    pub fn has_token(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= 1.0
+    }
+
+    // Spend a token, false when the budget is exhausted
+    pub fn try_take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        if !self.has_token(limit, now) {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills_at_the_configured_rate() {
+        let limit = RateLimit {
+            requests_per_sec: 2.0,
+            burst: 2,
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&limit, now);
+        assert!(bucket.try_take(&limit, now));
+        assert!(bucket.try_take(&limit, now));
+        assert!(!bucket.try_take(&limit, now));
+
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.try_take(&limit, later));
+        assert!(!bucket.try_take(&limit, later));
+        // Idle time does not build up more than the burst
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.try_take(&limit, much_later));
+        assert!(bucket.try_take(&limit, much_later));
+        assert!(!bucket.try_take(&limit, much_later));
+    }
+}
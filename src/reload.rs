@@ -0,0 +1,323 @@
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::config::{Config, ConfigSources};
+use crate::proxy_list::ProxySpec;
+use crate::site_router::{
+    build_cookies_handler, build_proxy_handler, build_proxy_pool, build_proxy_provider,
+    build_site_handler, proxy_entries, site_targeting, SiteRouter,
+};
+use crate::utils::load_pool_proxies;
+
+// Reloads the config file and the proxy files and applies the changes to the
+// running handlers, so cached cookies and in-flight requests survive a reload
+pub struct ConfigReloader {
+    sources: ConfigSources,
+    config: Config,
+    proxies: HashMap<String, Vec<ProxySpec>>,
+    router: Arc<SiteRouter>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        sources: ConfigSources,
+        config: Config,
+        proxies: HashMap<String, Vec<ProxySpec>>,
+        router: Arc<SiteRouter>,
+    ) -> Self {
+        ConfigReloader {
+            sources,
+            config,
+            proxies,
+            router,
+        }
+    }
+
+    pub async fn reload(&mut self) {
+        let loaded = Config::from_sources(&self.sources).and_then(|config| {
+            let proxies = load_pool_proxies(&config)?;
+            Ok((config, proxies))
+        });
+        let (config, proxies) = match loaded {
+            Ok(loaded) => loaded,
+            Err(errors) => {
+                error!(
+                    "Reload failed, keeping the current configuration. {}",
+                    errors
+                );
+                return;
+            }
+        };
+        if config.server.port != self.config.server.port
+            || config.server.host != self.config.server.host
+            || config.server.workers != self.config.server.workers
+        {
+            warn!("Server settings changed, they only apply after a restart");
+        }
+        if config.admin != self.config.admin {
+            warn!("Admin settings changed, they only apply after a restart");
+        }
+        if config.health_check != self.config.health_check {
+            warn!("Health check settings changed, they only apply after a restart");
+        }
+        if config.persistence != self.config.persistence {
+            warn!("Persistence settings changed, they only apply after a restart");
+        }
+
+        // Pools are updated in place so their proxies keep their health
+        let mut pools = HashMap::new();
+        for (name, pool_config) in &config.proxy_pools {
+            let pool = match (self.config.proxy_pools.get(name), self.router.pool(name)) {
+                (Some(old_config), Some(pool)) => {
+                    if pool_config != old_config || proxies[name] != self.proxies[name] {
+                        info!(
+                            "Updating proxy pool {}, {} proxies",
+                            name,
+                            proxies[name].len()
+                        );
+                        pool.set_entries(proxy_entries(pool_config, &proxies[name]));
+                        pool.set_health_policy(pool_config.health.clone());
+                        pool.set_subnet_policy(pool_config.subnets.clone());
+                        pool.set_provider(build_proxy_provider(pool_config));
+                    }
+                    pool
+                }
+                _ => {
+                    info!("Adding proxy pool {}", name);
+                    Arc::new(build_proxy_pool(pool_config, &proxies[name]))
+                }
+            };
+            pools.insert(name.clone(), pool);
+        }
+
+        let mut routes = Vec::new();
+        for site in &config.sites {
+            let pool = site.pool_name().map(|name| &pools[name]);
+            let old_site = self.config.sites.iter().find(|old| old.name == site.name);
+            let handler = match (old_site, self.router.handler(&site.name)) {
+                (Some(old_site), Some(handler)) => {
+                    // Changes to the pool itself were applied in place above
+                    let pool_changed = site.pool_name() != old_site.pool_name()
+                        || site.proxy_strategy != old_site.proxy_strategy
+                        || site.proxy_rate_limit != old_site.proxy_rate_limit;
+                    let provider_changed = match (&site.cookie_provider, &old_site.cookie_provider)
+                    {
+                        (Some(new), Some(old)) => {
+                            new != old
+                                || config.cookie_providers[&new.provider]
+                                    != self.config.cookie_providers[&old.provider]
+                        }
+                        (new, old) => new != old,
+                    };
+                    let targeting = site_targeting(site, &config);
+                    let targeting_changed = targeting != site_targeting(old_site, &self.config);
+                    let retry_policy = &config.retry_policies[&site.retry_policy];
+
+                    let running = handler.read().await;
+                    if pool_changed {
+                        info!("Swapping the proxy pool of site {}", site.name);
+                        running.set_proxy_handler(pool.map(|pool| build_proxy_handler(pool, site)));
+                    }
+                    if pool_changed
+                        || provider_changed
+                        || targeting_changed
+                        || site.cookie_url != old_site.cookie_url
+                    {
+                        // The cached cookies are kept and validated by the new provider on the next refresh
+                        info!("Swapping the cookie provider of site {}", site.name);
+                        running.set_cookies_handler(build_cookies_handler(
+                            site,
+                            &config,
+                            running.proxy_handler(),
+                        ));
+                    }
+                    if targeting_changed {
+                        info!("Swapping the proxy targeting of site {}", site.name);
+                        running.set_targeting(targeting);
+                    }
+                    if *retry_policy != self.config.retry_policies[&old_site.retry_policy] {
+                        info!("Swapping the retry policy of site {}", site.name);
+                        running.set_retry_policy(retry_policy.clone());
+                    }
+                    drop(running);
+                    handler
+                }
+                _ => {
+                    info!("Adding site {}", site.name);
+                    Arc::new(RwLock::new(build_site_handler(site, &config, pool)))
+                }
+            };
+            routes.push((site.clone(), handler));
+        }
+        for old_site in &self.config.sites {
+            if !config.sites.iter().any(|site| site.name == old_site.name) {
+                info!("Removing site {}", old_site.name);
+            }
+        }
+
+        self.router.replace_pools(pools);
+        self.router.replace(routes);
+        self.config = config;
+        self.proxies = proxies;
+        info!(
+            "Configuration reloaded, {} sites configured",
+            self.config.sites.len()
+        );
+    }
+
+    // Config file and proxy files whose changes trigger a reload
+    fn watched_files(&self) -> Vec<String> {
+        let mut files = vec![self.sources.file.clone()];
+        files.extend(
+            self.config
+                .proxy_pools
+                .values()
+                .map(|pool| pool.proxies_file.clone()),
+        );
+        files.sort();
+        files.dedup();
+        files
+    }
+
+    fn modified_times(&self) -> Vec<(String, Option<SystemTime>)> {
+        self.watched_files()
+            .into_iter()
+            .map(|file| {
+                let modified = fs::metadata(&file).and_then(|meta| meta.modified()).ok();
+                (file, modified)
+            })
+            .collect()
+    }
+}
+
+// Start the background tasks reloading on file changes and on SIGHUP
+pub fn spawn_watcher(reloader: ConfigReloader, poll_interval: Duration) {
+    let reloader = Arc::new(Mutex::new(reloader));
+
+    let poller = reloader.clone();
+    tokio::spawn(async move {
+        let mut last_seen = poller.lock().await.modified_times();
+        let mut ticker = tokio::time::interval(poll_interval);
+        loop {
+            ticker.tick().await;
+            let mut reloader = poller.lock().await;
+            let current = reloader.modified_times();
+            if current != last_seen {
+                info!("Config or proxy file changed, reloading");
+                reloader.reload().await;
+                last_seen = reloader.modified_times();
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading");
+            reloader.lock().await.reload().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_handler::ProxyContext;
+    use crate::proxy_health::ProxyOutcome;
+
+    fn config_yaml(sites: &[&str], proxies_file: &str, max_attempts: u32) -> String {
+        let mut config = format!(
+            "proxy_pools:\n  default:\n    provider:\n      kind: static\n    proxies_file: {}\nretry_policies:\n  default:\n    max_attempts: {}\nsites:\n",
+            proxies_file, max_attempts
+        );
+        for site in sites {
+            config.push_str(&format!(
+                "  - name: {0}\n    hosts: [www.{0}.com]\n    cookie_url: \"https://www.{0}.com/\"\n",
+                site
+            ));
+        }
+        config
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_handlers_cookies_and_pool_health() {
+        let dir = std::env::temp_dir().join(format!("webunlocker-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("webunlocker.yaml").to_string_lossy().to_string();
+        let proxies_file = dir.join("proxies.txt").to_string_lossy().to_string();
+        fs::write(&proxies_file, "203.0.113.7:8080\n203.0.113.8:8080\n").unwrap();
+        fs::write(
+            &config_file,
+            config_yaml(&["alpha", "beta"], &proxies_file, 3),
+        )
+        .unwrap();
+
+        let sources = ConfigSources {
+            file: config_file.clone(),
+            ..ConfigSources::default()
+        };
+        let config = Config::from_sources(&sources).unwrap();
+        let proxies = load_pool_proxies(&config).unwrap();
+        let router = Arc::new(SiteRouter::from_config(&config, &proxies));
+        let mut reloader = ConfigReloader::new(sources, config, proxies, router.clone());
+
+        let alpha = router.handler("alpha").unwrap();
+        let cookies = HashMap::from([("KP_UIDz".to_string(), "cached".to_string())]);
+        alpha.read().await.set_cookies(cookies.clone()).await;
+        let pool = router.pool("default").unwrap();
+        assert!(pool.ban("alpha", "http://203.0.113.7:8080/"));
+
+        // beta removed, gamma added, a proxy and a retry policy changed
+        fs::write(
+            &proxies_file,
+            "203.0.113.7:8080\n203.0.113.8:8080\n203.0.113.9:8080\n",
+        )
+        .unwrap();
+        fs::write(
+            &config_file,
+            config_yaml(&["alpha", "gamma"], &proxies_file, 5),
+        )
+        .unwrap();
+        reloader.reload().await;
+
+        let reloaded = router.handler("alpha").unwrap();
+        assert!(Arc::ptr_eq(&alpha, &reloaded));
+        assert_eq!(reloaded.read().await.current_cookies().await, cookies);
+        assert_eq!(reloaded.read().await.retry_policy().max_attempts, 5);
+        assert!(router.handler("beta").is_none());
+        assert!(router.handler("gamma").is_some());
+        let reloaded_pool = router.pool("default").unwrap();
+        assert!(Arc::ptr_eq(&pool, &reloaded_pool));
+        assert_eq!(pool.len(), 3);
+        // The ban still keeps the proxy away from the site
+        let proxy_handler = reloaded.read().await.proxy_handler().unwrap();
+        let ctx = ProxyContext::new("https://www.alpha.com/");
+        for _ in 0..20 {
+            let lease = proxy_handler.acquire(&ctx).await.unwrap();
+            assert_ne!(lease.url, "http://203.0.113.7:8080/");
+            proxy_handler.release(lease, ProxyOutcome::Success).await;
+        }
+
+        // An invalid config is rejected as a whole
+        fs::write(&config_file, "sites: not-a-list\n").unwrap();
+        reloader.reload().await;
+        assert!(router.handler("gamma").is_some());
+        assert_eq!(reloader.config.sites.len(), 2);
+        assert_eq!(pool.len(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,24 @@
 use log::{error, info, warn};
-use reqwest::{
-    header::{HeaderMap, HeaderValue, ACCEPT, COOKIE, USER_AGENT},
-    Client, Proxy,
-};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, COOKIE, USER_AGENT};
 
+use serde_derive::Deserialize;
+use std::sync::RwLock as StdRwLock;
+use std::time::Instant;
 use std::{collections::HashMap, sync::Arc};
 use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 
 use crate::{
+    config::lenient,
     cookies_handler::{BaseCookiesHandler, CookieException},
-    proxy_handler::ProxyHandler,
+    proxy_handler::{
+        proxy_client, ProxyContext, ProxyError, ProxyLease, ProxyTargeting, SharedAsyncProxyHandler,
+    },
+    proxy_health::ProxyOutcome,
+    proxy_latency::RequestTimings,
 };
 
+#[derive(Default)]
 pub struct CookieManager {
     cookies: Arc<RwLock<HashMap<String, String>>>, // Using RwLock for cookies
 }
@@ -37,9 +43,61 @@ impl CookieManager {
         *cookies = new_cookies; // Update cookies
     }
 }
-pub struct AsyncRequestHandler {
+// Retry behaviour of make_request, configurable per site
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    #[serde(deserialize_with = "lenient")]
+    pub max_attempts: u32,
+    #[serde(deserialize_with = "lenient")]
+    pub rate_limited_delay_secs: u64,
+    #[serde(deserialize_with = "lenient")]
+    pub forbidden_delay_secs: u64,
+    #[serde(deserialize_with = "lenient")]
+    pub error_delay_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            rate_limited_delay_secs: 1,
+            forbidden_delay_secs: 2,
+            error_delay_secs: 1,
+        }
+    }
+}
+
+// Parts of a handler that can be swapped while it is serving requests.
+// Each request works on the snapshot taken when it started.
+#[derive(Clone)]
+struct HandlerSettings {
     cookies_handler: Option<Arc<dyn BaseCookiesHandler + Send + Sync>>,
-    proxy_handler: Option<Arc<Mutex<dyn ProxyHandler + Send + Sync>>>, // Mutex inside Arc
+    proxy_handler: Option<SharedAsyncProxyHandler>,
+    retry_policy: RetryPolicy,
+    // BrightData modifiers of the site, None when its pool does not support them
+    targeting: Option<ProxyTargeting>,
+}
+
+impl HandlerSettings {
+    async fn acquire_proxy(&self, ctx: &ProxyContext) -> Result<Option<ProxyLease>, ProxyError> {
+        match self.proxy_handler {
+            Some(ref handler) => handler.acquire(ctx).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Leases go back to the handler that issued them, even once a reload
+    // swapped it, so the pool they came from stops counting them in flight
+    async fn release_proxy(&self, lease: Option<ProxyLease>, outcome: ProxyOutcome) {
+        if let (Some(lease), Some(handler)) = (lease, &self.proxy_handler) {
+            handler.release(lease, outcome).await;
+        }
+    }
+}
+
+pub struct AsyncRequestHandler {
+    settings: StdRwLock<Arc<HandlerSettings>>,
     lock: Arc<Mutex<bool>>,
     headers: HeaderMap,
     cookies: Arc<CookieManager>,
@@ -48,7 +106,7 @@ pub struct AsyncRequestHandler {
 impl AsyncRequestHandler {
     pub fn new(
         cookies_handler: Option<Arc<dyn BaseCookiesHandler + Send + Sync>>,
-        proxy_handler: Option<Arc<Mutex<dyn ProxyHandler + Send + Sync>>>, // Updated to Arc<Mutex>
+        proxy_handler: Option<SharedAsyncProxyHandler>,
     ) -> Self {
         let mut headers = HeaderMap::new();
         headers.insert(
@@ -61,14 +119,92 @@ impl AsyncRequestHandler {
         );
 
         AsyncRequestHandler {
-            cookies_handler,
-            proxy_handler,
+            settings: StdRwLock::new(Arc::new(HandlerSettings {
+                cookies_handler,
+                proxy_handler,
+                retry_policy: RetryPolicy::default(),
+                targeting: None,
+            })),
             lock: Arc::new(Mutex::new(false)),
             headers,
             cookies: Arc::new(CookieManager::new()),
         }
     }
 
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        self.set_retry_policy(retry_policy);
+        self
+    }
+
+    fn settings(&self) -> Arc<HandlerSettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    // Swap part of the settings, requests already running keep the previous ones
+    fn update_settings(&self, update: impl FnOnce(&mut HandlerSettings)) {
+        let mut settings = self.settings.write().unwrap();
+        let mut updated = (**settings).clone();
+        update(&mut updated);
+        *settings = Arc::new(updated);
+    }
+
+    pub fn set_cookies_handler(
+        &self,
+        cookies_handler: Option<Arc<dyn BaseCookiesHandler + Send + Sync>>,
+    ) {
+        self.update_settings(|settings| settings.cookies_handler = cookies_handler);
+    }
+
+    pub fn set_proxy_handler(&self, proxy_handler: Option<SharedAsyncProxyHandler>) {
+        self.update_settings(|settings| settings.proxy_handler = proxy_handler);
+    }
+
+    pub fn proxy_handler(&self) -> Option<SharedAsyncProxyHandler> {
+        self.settings().proxy_handler.clone()
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.settings().retry_policy.clone()
+    }
+
+    pub fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+        self.update_settings(|settings| settings.retry_policy = retry_policy);
+    }
+
+    pub fn with_targeting(self, targeting: Option<ProxyTargeting>) -> Self {
+        self.set_targeting(targeting);
+        self
+    }
+
+    pub fn set_targeting(&self, targeting: Option<ProxyTargeting>) {
+        self.update_settings(|settings| settings.targeting = targeting);
+    }
+
+    // Lease the next proxy from the proxy handler, None in direct mode
+    pub async fn next_proxy(&self, ctx: &ProxyContext) -> Result<Option<ProxyLease>, ProxyError> {
+        self.settings().acquire_proxy(ctx).await
+    }
+
+    // Cookies from the cookie provider of the site, they are not stored
+    pub async fn generate_cookies(&self) -> Result<HashMap<String, String>, CookieException> {
+        match self.settings().cookies_handler.clone() {
+            Some(cookies_handler) => cookies_handler.generate().await,
+            None => Err(CookieException {
+                message: "the site has no cookie provider".to_string(),
+            }),
+        }
+    }
+
+    // Snapshot of the cookies currently used for requests
+    pub async fn current_cookies(&self) -> HashMap<String, String> {
+        self.cookies.get_cookies().await
+    }
+
+    // Replace the cookies used for requests, e.g. with cookies generated elsewhere
+    pub async fn set_cookies(&self, cookies: HashMap<String, String>) {
+        self.cookies.set_cookies(cookies).await;
+    }
+
     pub async fn refresh(&self, url: &str) {
         // Acquire the lock
         let mut guard = match self.lock.try_lock() {
@@ -83,8 +219,8 @@ impl AsyncRequestHandler {
         };
         println!("The task locked by {:?}", url);
         info!("Refreshing cookies.");
-        let cookies_handler = self.cookies_handler.clone(); // Clone the handler for async operations
-        let current_cookies = self.cookies.get_cookies().await;
+        let cookies_handler = self.settings().cookies_handler.clone(); // Clone the handler for async operations
+        let current_cookies = self.current_cookies().await;
         *guard = true;
         if let Some(cookies_handler) = cookies_handler {
             match cookies_handler.validate(&current_cookies).await {
@@ -117,32 +253,53 @@ impl AsyncRequestHandler {
     }
 
     pub async fn make_request(&self, url: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.make_request_with(url, &ProxyTargeting::default())
+            .await
+    }
+
+    // Request with BrightData modifiers overriding the ones of the site
+    pub async fn make_request_with(
+        &self,
+        url: &str,
+        targeting: &ProxyTargeting,
+    ) -> Result<String, Box<dyn std::error::Error>> {
         println!("Starting request to URL: {}", url);
         info!("Starting request to URL: {}", url);
 
+        let settings = self.settings();
+        let targeting = match settings.targeting {
+            Some(ref site_targeting) => site_targeting.merged(targeting),
+            None => {
+                if !targeting.is_empty() {
+                    warn!("The proxy pool of the site does not support targeting, ignoring it");
+                }
+                ProxyTargeting::default()
+            }
+        };
+        let ctx = ProxyContext {
+            url: url.to_string(),
+            targeting,
+        };
         let mut attempts = 0;
-        let max_attempts = 3; // Define max attempts here for easier adjustments
+        let max_attempts = settings.retry_policy.max_attempts;
         loop {
             let guard = self.lock.lock().await;
             // println!("processing after lock url: {:?}", url);
             drop(guard);
             attempts += 1;
 
-            // Proxy setup logic
-            let proxy_url = if let Some(ref handler) = self.proxy_handler {
-                let handler = handler.lock().await; // Await the lock
-                handler.get_proxy()
-            } else {
-                None
-            };
-
-            let proxy = match proxy_url {
-                Some(ref url) => Proxy::all(url)?,
-                None => Proxy::all("")?, // Default proxy (could be handled better)
+            // Every attempt uses the proxy pool of the settings the request
+            // started with, sites without one connect directly. An exhausted
+            // pool fails the request rather than falling back to the IP of the server.
+            let mut lease = settings.acquire_proxy(&ctx).await?;
+            let client = match proxy_client(lease.as_ref().map(|lease| lease.url.as_str())) {
+                Ok(client) => client,
+                Err(e) => {
+                    settings.release_proxy(lease, ProxyOutcome::Failed).await;
+                    return Err(e.into());
+                }
             };
 
-            let client = Client::builder().proxy(proxy).build()?;
-
             let mut headers = self.headers.clone();
             let cookie_string: String = self
                 .cookies
@@ -161,15 +318,52 @@ impl AsyncRequestHandler {
                 })?,
             );
 
-            let response = client.get(url).headers(headers).send().await?;
+            // The send also connects to the proxy, the client is new for every attempt
+            let sent_at = Instant::now();
+            let response = match client.get(url).headers(headers).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    settings
+                        .release_proxy(lease.take(), ProxyOutcome::Failed)
+                        .await;
+                    return Err(e.into());
+                }
+            };
+            // The latency of the proxy is recorded whatever the status
+            if let Some(ref mut lease) = lease {
+                lease.timings = Some(RequestTimings {
+                    first_byte: sent_at.elapsed(),
+                    total: None,
+                });
+            }
 
-            match response.status().as_u16() {
+            let status = response.status().as_u16();
+            if status != 200 {
+                settings
+                    .release_proxy(lease.take(), ProxyOutcome::from_status(status))
+                    .await;
+            }
+            match status {
                 200 => {
-                    let body = response.text().await?;
+                    let body = response.text().await;
+                    if let (Ok(_), Some(timings)) = (
+                        &body,
+                        lease.as_mut().and_then(|lease| lease.timings.as_mut()),
+                    ) {
+                        timings.total = Some(sent_at.elapsed());
+                    }
+                    // An empty body means the cookies are stale, not that the proxy is bad
+                    let outcome = match body {
+                        Ok(ref body) if body.is_empty() => ProxyOutcome::Throttled,
+                        Ok(_) => ProxyOutcome::Success,
+                        Err(_) => ProxyOutcome::Failed,
+                    };
+                    settings.release_proxy(lease.take(), outcome).await;
+                    let body = body?;
                     if body.is_empty() {
                         println!("The response is empty");
                         warn!("The response is empty");
-                        self.refresh(&url).await;
+                        self.refresh(url).await;
                         continue;
                     }
                     return Ok(body);
@@ -183,8 +377,11 @@ impl AsyncRequestHandler {
                         "Received status 429 (Too Many Requests). Retrying... {:?}",
                         url
                     );
-                    self.refresh(&url).await;
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    self.refresh(url).await;
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        settings.retry_policy.rate_limited_delay_secs,
+                    ))
+                    .await;
                 }
                 403 => {
                     println!(
@@ -193,23 +390,83 @@ impl AsyncRequestHandler {
                     error!(
                         "Received status 403 (Forbidden). Trying to change proxy or other actions."
                     );
-                    if let Some(ref handler) = self.proxy_handler {
-                        let mut handler = handler.lock().await; // Await the lock
-                        handler.remove(&proxy_url.unwrap())
-                    } ;
-                    // You might want to add proxy removal or change logic here
-                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                    // The proxy was released as blocked, the pool cools it down
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        settings.retry_policy.forbidden_delay_secs,
+                    ))
+                    .await;
                 }
                 _ => {
                     println!("Request failed with status: {}", response.status());
                     error!("Request failed with status: {}", response.status());
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    tokio::time::sleep(tokio::time::Duration::from_secs(
+                        settings.retry_policy.error_delay_secs,
+                    ))
+                    .await;
                 }
             }
 
             if attempts >= max_attempts {
-                return Err("Failed to make successful request after 3 attempts".into());
+                return Err(format!(
+                    "Failed to make successful request after {} attempts",
+                    max_attempts
+                )
+                .into());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::proxy_handler::SyncProxyAdapter;
+    use crate::proxy_pool::{ProxyEntry, ProxyPool, SelectionStrategy};
+
+    fn pool(url: &str) -> Arc<ProxyPool> {
+        Arc::new(ProxyPool::new(vec![ProxyEntry::new(url.to_string())]))
+    }
+
+    #[tokio::test]
+    async fn lease_goes_back_to_the_pool_it_came_from() {
+        // Local proxy answering every request once the handler was moved to another pool
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+        let old_pool = pool(&proxy_url);
+        let new_pool = pool("http://127.0.0.1:9");
+        let handler = Arc::new(AsyncRequestHandler::new(
+            None,
+            Some(SyncProxyAdapter::shared(
+                old_pool.site("example.com", SelectionStrategy::Random),
+            )),
+        ));
+
+        let swapped = handler.clone();
+        let swap_to = new_pool.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            swapped.set_proxy_handler(Some(SyncProxyAdapter::shared(
+                swap_to.site("example.com", SelectionStrategy::Random),
+            )));
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+        });
+
+        let body = handler.make_request("http://example.com/").await.unwrap();
+        assert_eq!(body, "ok");
+        assert_eq!(old_pool.status()[0].in_flight, 0);
+        assert!(old_pool.status()[0].latency.first_byte.count() > 0);
+        assert_eq!(new_pool.status()[0].latency.first_byte.count(), 0);
+    }
+}
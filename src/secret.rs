@@ -0,0 +1,73 @@
+use serde::de::{self, Deserializer};
+use std::fmt;
+use std::fs;
+
+// Values starting with this scheme are read from the file that follows it,
+// e.g. `secret:///run/secrets/proxy_password`
+pub const SECRET_SCHEME: &str = "secret://";
+
+// A credential that never shows up in Debug or Display output
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl Secret {
+    // Read a secret from a mounted file, dropping the trailing newline
+    pub fn from_file(path: &str) -> std::io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(Secret(contents.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(\"[redacted]\")")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = <String as de::Deserialize>::deserialize(deserializer)?;
+        match value.strip_prefix(SECRET_SCHEME) {
+            Some(path) => Secret::from_file(path).map_err(|e| {
+                de::Error::custom(format!("failed to read secret file {}: {}", path, e))
+            }),
+            None => Ok(Secret(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatting_never_shows_the_value() {
+        let secret = Secret::from("hunter2".to_string());
+        assert_eq!(format!("{:?}", secret), "Secret(\"[redacted]\")");
+        assert_eq!(secret.to_string(), "[redacted]");
+        assert_eq!(secret.expose(), "hunter2");
+    }
+}
@@ -0,0 +1,115 @@
+use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use log::info;
+use serde_derive::Deserialize;
+use std::time::Duration;
+
+use crate::admin::{self, AdminState};
+use crate::config::{Config, ConfigSources};
+use crate::proxy_handler::ProxyTargeting;
+use crate::reload::{spawn_watcher, ConfigReloader};
+use crate::unlocker::{Unlocker, UnlockerError};
+use crate::utils::load_pool_proxies;
+
+// Run the HTTP API until the process is stopped
+pub async fn serve(sources: ConfigSources, config: Config) -> std::io::Result<()> {
+    info!("Total sites configured {}", config.sites.len());
+
+    // Build one handler per site, the reloader compares later changes of the
+    // proxies files against the same read
+    let proxies = load_pool_proxies(&config).map_err(std::io::Error::other)?;
+    let unlocker = Unlocker::from_proxies(&config, &proxies);
+
+    // Bans and cooldowns of the previous run still apply
+    let persistence = config.persistence.clone();
+    unlocker.persist_state(&persistence);
+
+    if config.health_check.enabled {
+        unlocker.spawn_health_checker(config.health_check.clone());
+    }
+
+    // The admin API is only mounted when a token is configured
+    let admin = config.admin.token.clone().map(|token| {
+        web::Data::new(AdminState {
+            token,
+            router: unlocker.router(),
+        })
+    });
+
+    let server = (config.server.host.clone(), config.server.port);
+    let workers = config.server.workers;
+    if config.reload.enabled {
+        let poll_interval = Duration::from_secs(config.reload.poll_interval_secs);
+        let reloader = ConfigReloader::new(sources, config, proxies, unlocker.router());
+        spawn_watcher(reloader, poll_interval);
+    }
+    let unlocker = web::Data::new(unlocker);
+    let saver = unlocker.clone();
+
+    // Start the HTTP server
+    HttpServer::new(move || {
+        let app = App::new()
+            .app_data(unlocker.clone()) // One unlocker holding the handler of every site
+            .route("/", web::get().to(healthcheck))
+            .route("/request", web::get().to(request_handler)); // Route all requests to the same handler
+        match admin {
+            Some(ref admin) => app.service(
+                web::scope("/admin")
+                    .app_data(admin.clone())
+                    .configure(admin::routes),
+            ),
+            None => app,
+        }
+    })
+    .bind(server)?
+    .workers(workers)
+    .run()
+    .await?;
+
+    saver.save_state(&persistence);
+    Ok(())
+}
+
+async fn healthcheck() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+async fn request_handler(
+    request_data: web::Query<RequestData>,
+    unlocker: web::Data<Unlocker>,
+) -> impl Responder {
+    println!("{:?}", request_data);
+
+    let targeting = ProxyTargeting {
+        country: request_data.country.clone(),
+        city: request_data.city.clone(),
+        session: request_data.session.clone(),
+    };
+    match unlocker.fetch_with(&request_data.url, &targeting).await {
+        Ok(body) => {
+            HttpResponse::Ok().json(serde_json::json!({ "status_code": 200, "body": body }))
+        }
+        Err(UnlockerError::InvalidUrl(_)) => HttpResponse::BadRequest()
+            .json(serde_json::json!({ "status_code": 400, "body": "" ,"msg":"Invalid URL format"})),
+        // The domain does not belong to a configured site
+        Err(UnlockerError::UnknownSite(_)) => HttpResponse::BadRequest().json(
+            serde_json::json!({ "status_code": 400, "body": "" ,"msg":"URL domain not allowed"}),
+        ),
+        Err(UnlockerError::InvalidTargeting(message)) => HttpResponse::BadRequest()
+            .json(serde_json::json!({ "status_code": 400, "body": "", "msg": message })),
+        // The pool of the site is exhausted, retrying later may succeed
+        Err(UnlockerError::NoProxyAvailable(_)) => HttpResponse::ServiceUnavailable().json(
+            serde_json::json!({ "status_code": 503, "body": "", "msg": "No proxy available" }),
+        ),
+        Err(UnlockerError::Request(_)) => HttpResponse::TooManyRequests()
+            .json(serde_json::json!({ "status_code": 429, "body": "" })),
+    }
+}
+
+#[derive(Deserialize, Debug)] // Add the Debug derive here
+struct RequestData {
+    url: String,
+    // BrightData modifiers overriding the ones of the site
+    country: Option<String>,
+    city: Option<String>,
+    session: Option<String>,
+}
@@ -0,0 +1,159 @@
+use serde_derive::Deserialize;
+
+use crate::config::{lenient, Config, ConfigError, ProxyProviderConfig};
+use crate::proxy_handler::ProxyTargeting;
+use crate::proxy_pool::SelectionStrategy;
+use crate::rate_limit::RateLimit;
+
+// Cookie provider used to generate and validate cookies for a site,
+// referencing one of the `cookie_providers` of the config
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct SiteCookieConfig {
+    pub provider: String,
+    #[serde(default, deserialize_with = "lenient")]
+    pub premium_proxy: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SiteConfig {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub cookie_url: String,
+    #[serde(default)]
+    pub cookie_provider: Option<SiteCookieConfig>,
+    #[serde(default = "default_name")]
+    pub proxy_pool: String,
+    // Requests go straight to the site, the proxy pool is not used
+    #[serde(default, deserialize_with = "lenient")]
+    pub direct: bool,
+    #[serde(default)]
+    pub proxy_strategy: SelectionStrategy,
+    // Requests each proxy may send to the site, unlimited when not set
+    #[serde(default)]
+    pub proxy_rate_limit: Option<RateLimit>,
+    // BrightData country, city and session of the proxies used for the site
+    #[serde(default)]
+    pub proxy_targeting: ProxyTargeting,
+    #[serde(default = "default_name")]
+    pub retry_policy: String,
+}
+
+fn default_name() -> String {
+    "default".to_string()
+}
+
+impl SiteConfig {
+    // Site without cookie provider using the default proxy pool and retry policy
+    pub fn new(name: &str, hosts: &[&str], cookie_url: &str) -> Self {
+        SiteConfig {
+            name: name.to_string(),
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            cookie_url: cookie_url.to_string(),
+            cookie_provider: None,
+            proxy_pool: default_name(),
+            direct: false,
+            proxy_strategy: SelectionStrategy::default(),
+            proxy_rate_limit: None,
+            proxy_targeting: ProxyTargeting::default(),
+            retry_policy: default_name(),
+        }
+    }
+
+    // Proxy pool requests of the site go through, None in direct mode
+    pub fn pool_name(&self) -> Option<&str> {
+        (!self.direct).then_some(self.proxy_pool.as_str())
+    }
+
+    // Check if the host matches one of the site patterns.
+    // A pattern is either an exact host or a wildcard like `*.example.com`
+    pub fn matches_host(&self, host: &str) -> bool {
+        self.hosts.iter().any(|pattern| host_matches(pattern, host))
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => host == pattern,
+    }
+}
+
+// Check the site definitions and the pools, providers and policies they reference
+pub fn validate_sites(config: &Config) -> Vec<ConfigError> {
+    let sites = &config.sites;
+    let mut errors = Vec::new();
+    let invalid = |key: String, message: String| ConfigError::Invalid { key, message };
+
+    if sites.is_empty() {
+        errors.push(invalid("sites".to_string(), "no sites defined".to_string()));
+    }
+    for (index, site) in sites.iter().enumerate() {
+        if sites[..index].iter().any(|s| s.name == site.name) {
+            errors.push(invalid(
+                format!("sites.{}", site.name),
+                "site is defined more than once".to_string(),
+            ));
+        }
+        if site.hosts.is_empty() {
+            errors.push(invalid(
+                format!("sites.{}.hosts", site.name),
+                "no hosts defined".to_string(),
+            ));
+        }
+        match site.pool_name().map(|name| config.proxy_pools.get(name)) {
+            None => {
+                if !site.proxy_targeting.is_empty() {
+                    errors.push(invalid(
+                        format!("sites.{}.proxy_targeting", site.name),
+                        "not supported by direct sites".to_string(),
+                    ));
+                }
+            }
+            Some(None) => errors.push(invalid(
+                format!("sites.{}.proxy_pool", site.name),
+                format!("unknown proxy pool {:?}", site.proxy_pool),
+            )),
+            Some(Some(pool)) => {
+                if !site.proxy_targeting.is_empty()
+                    && pool.provider != ProxyProviderConfig::BrightData
+                {
+                    errors.push(invalid(
+                        format!("sites.{}.proxy_targeting", site.name),
+                        "only supported by bright_data proxy pools".to_string(),
+                    ));
+                }
+            }
+        }
+        if let Err(message) = site.proxy_targeting.validate() {
+            errors.push(invalid(
+                format!("sites.{}.proxy_targeting", site.name),
+                message,
+            ));
+        }
+        if let Some(Err(message)) = site.proxy_rate_limit.as_ref().map(RateLimit::validate) {
+            errors.push(invalid(
+                format!("sites.{}.proxy_rate_limit", site.name),
+                message,
+            ));
+        }
+        if !config.retry_policies.contains_key(&site.retry_policy) {
+            errors.push(invalid(
+                format!("sites.{}.retry_policy", site.name),
+                format!("unknown retry policy {:?}", site.retry_policy),
+            ));
+        }
+        if let Some(ref cookies) = site.cookie_provider {
+            if !config.cookie_providers.contains_key(&cookies.provider) {
+                errors.push(invalid(
+                    format!("sites.{}.cookie_provider", site.name),
+                    format!("unknown cookie provider {:?}", cookies.provider),
+                ));
+            }
+        }
+    }
+    errors
+}
@@ -0,0 +1,190 @@
+use log::info;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+use crate::config::{Config, ConfigErrors, HealthCheckConfig, PersistenceConfig};
+use crate::cookies_handler::CookieException;
+use crate::proxy_checker::{probe_proxies, spawn_checker, ProxyProbe};
+use crate::proxy_handler::{ProxyError, ProxyTargeting};
+use crate::proxy_list::ProxySpec;
+use crate::proxy_state;
+use crate::request_handler::AsyncRequestHandler;
+use crate::site_registry::SiteConfig;
+use crate::site_router::SiteRouter;
+use crate::utils::load_pool_proxies;
+
+#[derive(Debug)]
+pub enum UnlockerError {
+    InvalidUrl(String),
+    UnknownSite(String),
+    InvalidTargeting(String),
+    // Every proxy of the site pool is banned, cooling down or unreachable
+    NoProxyAvailable(String),
+    Request(String),
+}
+
+impl fmt::Display for UnlockerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnlockerError::InvalidUrl(url) => write!(f, "Invalid URL format: {}", url),
+            UnlockerError::UnknownSite(host) => write!(f, "No site configured for host {}", host),
+            UnlockerError::InvalidTargeting(message) => write!(f, "Invalid targeting: {}", message),
+            UnlockerError::NoProxyAvailable(site) => {
+                write!(f, "No proxy available for site {}", site)
+            }
+            UnlockerError::Request(message) => write!(f, "Request failed: {}", message),
+        }
+    }
+}
+
+impl Error for UnlockerError {}
+
+// Entry point of the library: dispatches URLs to the handler of their site.
+// Cheap to clone, clones share the same sites, cookies and proxy pools.
+#[derive(Clone)]
+pub struct Unlocker {
+    router: Arc<SiteRouter>,
+}
+
+impl Unlocker {
+    pub fn builder() -> UnlockerBuilder {
+        UnlockerBuilder {
+            router: SiteRouter::new(),
+        }
+    }
+
+    // Build the handlers of every site of a validated config,
+    // fails when a proxies file cannot be read or parsed
+    pub fn from_config(config: &Config) -> Result<Unlocker, ConfigErrors> {
+        let proxies = load_pool_proxies(config)?;
+        Ok(Unlocker::from_proxies(config, &proxies))
+    }
+
+    // Build the handlers from the proxies of every pool, already loaded
+    pub(crate) fn from_proxies(
+        config: &Config,
+        proxies: &HashMap<String, Vec<ProxySpec>>,
+    ) -> Unlocker {
+        Unlocker {
+            router: Arc::new(SiteRouter::from_config(config, proxies)),
+        }
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn router(&self) -> Arc<SiteRouter> {
+        self.router.clone()
+    }
+
+    // Restore the proxy health and bans saved by a previous run, then save them
+    // in the background. Does nothing while no state file is set.
+    pub fn persist_state(&self, config: &PersistenceConfig) {
+        if config.file.is_some() {
+            proxy_state::restore(&self.router, config);
+            proxy_state::spawn_saver(self.router.clone(), config.clone());
+        }
+    }
+
+    // Save the proxy health and bans right away, e.g. on shutdown
+    pub fn save_state(&self, config: &PersistenceConfig) {
+        proxy_state::save(&self.router, config);
+    }
+
+    // Probe the proxies of every pool in the background
+    pub fn spawn_health_checker(&self, config: HealthCheckConfig) {
+        spawn_checker(self.router.clone(), config);
+    }
+
+    // Proxy pools with the number of proxies they hold, sorted by name
+    pub fn pool_sizes(&self) -> Vec<(String, usize)> {
+        let mut sizes: Vec<(String, usize)> = self
+            .router
+            .pools()
+            .into_iter()
+            .map(|(name, pool)| (name, pool.len()))
+            .collect();
+        sizes.sort();
+        sizes
+    }
+
+    // Probe every proxy of a pool against a URL, `concurrency` at a time, in
+    // the order of the pool. None when there is no pool with that name.
+    pub async fn probe_pool(
+        &self,
+        pool: &str,
+        url: &str,
+        timeout: Duration,
+        concurrency: usize,
+    ) -> Option<Vec<ProxyProbe>> {
+        let pool = self.router.pool(pool)?;
+        Some(probe_proxies(pool.urls(), url, timeout, concurrency).await)
+    }
+
+    // Generate cookies with the cookie provider of a site, without storing them
+    pub async fn generate_cookies(
+        &self,
+        site: &str,
+    ) -> Result<HashMap<String, String>, CookieException> {
+        let handler = self.router.handler(site).ok_or_else(|| CookieException {
+            message: format!("unknown site {:?}", site),
+        })?;
+        let handler = handler.read().await;
+        handler.generate_cookies().await
+    }
+
+    // Fetch a URL through the handler of its site and return the body
+    pub async fn fetch(&self, url: &str) -> Result<String, UnlockerError> {
+        self.fetch_with(url, &ProxyTargeting::default()).await
+    }
+
+    // Fetch with BrightData country, city or session modifiers for this request
+    pub async fn fetch_with(
+        &self,
+        url: &str,
+        targeting: &ProxyTargeting,
+    ) -> Result<String, UnlockerError> {
+        targeting
+            .validate()
+            .map_err(UnlockerError::InvalidTargeting)?;
+        let parsed_url = Url::parse(url).map_err(|_| UnlockerError::InvalidUrl(url.to_string()))?;
+        let host = parsed_url.host_str().unwrap_or("");
+        let (site, handler) = self
+            .router
+            .route(host)
+            .ok_or_else(|| UnlockerError::UnknownSite(host.to_string()))?;
+
+        info!("Dispatching {} to site {}", parsed_url, site.name);
+        let handler = handler.read().await;
+        handler
+            .make_request_with(parsed_url.as_ref(), targeting)
+            .await
+            .map_err(|e| match e.downcast_ref::<ProxyError>() {
+                Some(ProxyError::NoProxyAvailable) => {
+                    UnlockerError::NoProxyAvailable(site.name.clone())
+                }
+                _ => UnlockerError::Request(e.to_string()),
+            })
+    }
+}
+
+pub struct UnlockerBuilder {
+    router: SiteRouter,
+}
+
+impl UnlockerBuilder {
+    // Register a site with its own handler, the first matching site wins
+    pub fn site(mut self, site: SiteConfig, handler: AsyncRequestHandler) -> Self {
+        self.router.add(site, Arc::new(RwLock::new(handler)));
+        self
+    }
+
+    pub fn build(self) -> Unlocker {
+        Unlocker {
+            router: Arc::new(self.router),
+        }
+    }
+}
@@ -1,7 +1,107 @@
+use log::warn;
+use std::collections::HashMap;
 use std::fs;
-pub fn load_proxies(path: &str) -> Vec<String> {
-    match fs::read_to_string(path) {
-        Ok(contents) => contents.lines().map(|s| s.to_string()).collect(),
-        Err(_) => vec![],
+use std::io::ErrorKind;
+
+use crate::config::{Config, ConfigError, ConfigErrors};
+use crate::proxy_list::{parse_proxy_list, ProxySpec};
+use crate::site_router::build_proxy_provider;
+
+// Proxies listed in a file, a missing file is an empty list
+pub fn load_proxies(path: &str) -> Result<Vec<ProxySpec>, ConfigErrors> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!("Proxies file {} not found, the pool is empty", path);
+            return Ok(vec![]);
+        }
+        Err(source) => {
+            return Err(ConfigError::Io {
+                path: path.to_string(),
+                source,
+            }
+            .into())
+        }
+    };
+    parse_proxy_list(&contents).map_err(|errors| {
+        ConfigErrors(
+            errors
+                .into_iter()
+                .map(|error| ConfigError::Parse {
+                    path: path.to_string(),
+                    message: error.to_string(),
+                })
+                .collect(),
+        )
+    })
+}
+
+// Proxies of every pool of the config, keyed by pool name. Every proxy of
+// a pool has to resolve to a URL with the provider of the pool.
+pub fn load_pool_proxies(config: &Config) -> Result<HashMap<String, Vec<ProxySpec>>, ConfigErrors> {
+    let mut proxies = HashMap::new();
+    let mut errors = Vec::new();
+    for (name, pool) in &config.proxy_pools {
+        match load_proxies(&pool.proxies_file) {
+            Ok(pool_proxies) => {
+                let provider = build_proxy_provider(pool);
+                for proxy in provider.proxies(&pool_proxies) {
+                    if let Err(message) = provider.proxy_url(&proxy) {
+                        errors.push(ConfigError::Invalid {
+                            key: format!("proxy_pools.{}", name),
+                            message: format!("proxy {}: {}", proxy, message),
+                        });
+                    }
+                }
+                proxies.insert(name.clone(), pool_proxies);
+            }
+            Err(ConfigErrors(pool_errors)) => errors.extend(pool_errors),
+        }
+    }
+    if errors.is_empty() {
+        Ok(proxies)
+    } else {
+        Err(ConfigErrors(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigSources;
+
+    #[test]
+    fn unresolved_placeholders_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("webunlocker-utils-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_file = dir.join("webunlocker.yaml");
+        let proxies_file = dir.join("proxies.txt");
+        fs::write(&proxies_file, "203.0.113.7 country=au\n203.0.113.8\n").unwrap();
+        fs::write(
+            &config_file,
+            format!(
+                "proxy_pools:\n  default:\n    provider:\n      kind: gateway\n      username_template: \"{{username}}-country-{{country}}-ip-{{ip}}\"\n    username: customer\n    password: secret\n    host: gw.example.com\n    port: 7777\n    proxies_file: {}\nsites:\n  - name: site\n    hosts: [www.site.com]\n    cookie_url: \"https://www.site.com/\"\n",
+                proxies_file.display()
+            ),
+        )
+        .unwrap();
+
+        let config = Config::from_sources(&ConfigSources {
+            file: config_file.to_string_lossy().to_string(),
+            ..ConfigSources::default()
+        })
+        .unwrap();
+        let messages: Vec<String> = load_pool_proxies(&config)
+            .unwrap_err()
+            .0
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            messages,
+            ["proxy_pools.default is invalid: proxy 203.0.113.8: no value for {country} of the username template"]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }